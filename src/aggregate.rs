@@ -0,0 +1,303 @@
+//! aggregate queries, computed while scanning the table (only the groups are kept in memory)
+
+use serde::{Serialize, Deserialize};
+use rustc_hash::FxHashMap;
+use anyhow::{Result, bail};
+use crate::{
+  database::{Database, RwData},
+  operations::{DbRowColumnValue, DbQueryKey},
+  query::{DbPredicate, compare},
+};
+
+/// For example `{"Sum": "price"}`, `{"Count": null}` counts rows, `{"Count": "column"}` values that are not NULL\
+/// Other aggregates skip NULL values too, and are NULL if there's nothing left
+#[derive(Serialize, Deserialize)]
+pub enum DbAggregate {
+  Count(Option<DbQueryKey>),
+  Sum(DbQueryKey),
+  Min(DbQueryKey),
+  Max(DbQueryKey),
+  Avg(DbQueryKey),
+}
+
+impl DbAggregate {
+  fn key(&self) -> Option<&DbQueryKey> {
+    match self {
+      DbAggregate::Count(key) => key.as_ref(),
+      DbAggregate::Sum(key) | DbAggregate::Min(key) | DbAggregate::Max(key) | DbAggregate::Avg(key) => Some(key),
+    }
+  }
+
+  /// How `having` refers to the result, for example `sum(price)`, or just `count` for `{"Count": null}`
+  fn name(&self) -> String {
+    let function = match self {
+      DbAggregate::Count(_) => "count",
+      DbAggregate::Sum(_) => "sum",
+      DbAggregate::Min(_) => "min",
+      DbAggregate::Max(_) => "max",
+      DbAggregate::Avg(_) => "avg",
+    };
+    match self.key() {
+      Some(key) => format!("{function}({})", key_name(key)),
+      None => function.to_string(),
+    }
+  }
+}
+
+/// `name`, or `customer.name` for pointer keys
+fn key_name(key: &DbQueryKey) -> String {
+  match key {
+    DbQueryKey::Simple(name) => name.clone(),
+    DbQueryKey::Pointer(path) => path.join("."),
+  }
+}
+
+fn as_number(function: &str, value: &DbRowColumnValue) -> Result<f64> {
+  match value {
+    DbRowColumnValue::Integer(i) => Ok(*i as f64),
+    DbRowColumnValue::Float(f) => Ok(*f),
+    value => bail!("{function} needs numbers, got {}", value.kind()),
+  }
+}
+
+/// Hashable version of a `group_by` value
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum GroupValue {
+  Null,
+  String(String),
+  Blob(Vec<u8>),
+  Integer(i128),
+  /// bits of the `f64`
+  Float(u64),
+}
+
+impl From<DbRowColumnValue> for GroupValue {
+  fn from(value: DbRowColumnValue) -> Self {
+    match value {
+      DbRowColumnValue::Null => Self::Null,
+      DbRowColumnValue::String(s) => Self::String(s),
+      DbRowColumnValue::Blob(b) => Self::Blob(b),
+      DbRowColumnValue::Integer(i) => Self::Integer(i),
+      DbRowColumnValue::Float(f) => Self::Float(f.to_bits()),
+    }
+  }
+}
+
+impl From<GroupValue> for DbRowColumnValue {
+  fn from(value: GroupValue) -> Self {
+    match value {
+      GroupValue::Null => Self::Null,
+      GroupValue::String(s) => Self::String(s),
+      GroupValue::Blob(b) => Self::Blob(b),
+      GroupValue::Integer(i) => Self::Integer(i),
+      GroupValue::Float(bits) => Self::Float(f64::from_bits(bits)),
+    }
+  }
+}
+
+/// Running state of an aggregate in a group
+enum Accumulator {
+  Count(u64),
+  Sum(Option<DbRowColumnValue>),
+  Min(Option<DbRowColumnValue>),
+  Max(Option<DbRowColumnValue>),
+  Avg(f64, u64),
+}
+
+impl Accumulator {
+  fn new(aggregate: &DbAggregate) -> Self {
+    match aggregate {
+      DbAggregate::Count(_) => Self::Count(0),
+      DbAggregate::Sum(_) => Self::Sum(None),
+      DbAggregate::Min(_) => Self::Min(None),
+      DbAggregate::Max(_) => Self::Max(None),
+      DbAggregate::Avg(_) => Self::Avg(0., 0),
+    }
+  }
+
+  /// `value` is `None` for `{"Count": null}`, which counts every row
+  fn add(&mut self, value: Option<DbRowColumnValue>) -> Result<()> {
+    let value = match value {
+      Some(DbRowColumnValue::Null) => return Ok(()),
+      Some(value) => value,
+      None => DbRowColumnValue::Null,
+    };
+    match self {
+      Self::Count(count) => *count += 1,
+      //integers stay integers, as long as there are no floats
+      Self::Sum(sum) => *sum = Some(match (sum.take(), value) {
+        (None, DbRowColumnValue::Integer(i)) => DbRowColumnValue::Integer(i),
+        (Some(DbRowColumnValue::Integer(a)), DbRowColumnValue::Integer(b)) => {
+          let Some(sum) = a.checked_add(b) else { bail!("sum is out of range") };
+          DbRowColumnValue::Integer(sum)
+        },
+        (sum, value) => {
+          let sum = sum.map_or(Ok(0.), |sum| as_number("SUM", &sum))?;
+          DbRowColumnValue::Float(sum + as_number("SUM", &value)?)
+        },
+      }),
+      Self::Min(min) => if min.as_ref().map_or(Ok(true), |min| compare(&value, min).map(|o| o.is_some_and(|o| o.is_lt())))? {
+        *min = Some(value);
+      },
+      Self::Max(max) => if max.as_ref().map_or(Ok(true), |max| compare(&value, max).map(|o| o.is_some_and(|o| o.is_gt())))? {
+        *max = Some(value);
+      },
+      Self::Avg(sum, count) => {
+        *sum += as_number("AVG", &value)?;
+        *count += 1;
+      },
+    }
+    Ok(())
+  }
+
+  fn finish(self) -> DbRowColumnValue {
+    match self {
+      Self::Count(count) => DbRowColumnValue::Integer(count.into()),
+      Self::Sum(value) | Self::Min(value) | Self::Max(value) => value.unwrap_or(DbRowColumnValue::Null),
+      Self::Avg(_, 0) => DbRowColumnValue::Null,
+      Self::Avg(sum, count) => DbRowColumnValue::Float(sum / count as f64),
+    }
+  }
+}
+
+impl<T: RwData> Database<T> {
+  /// One result row per group: the `group_by` values followed by the aggregates, groups in the order they were first seen\
+  /// Without `group_by`, all matching rows are a single group (even if there are none)\
+  /// `having` filters the groups, using the names of the `group_by` keys and of the aggregates (see `DbAggregate::name`)
+  pub fn table_aggregate(
+    &mut self,
+    name: &str,
+    group_by: &[DbQueryKey],
+    aggregates: &[DbAggregate],
+    filter: Option<&DbPredicate>,
+    having: Option<&DbPredicate>,
+  ) -> Result<Vec<Vec<DbRowColumnValue>>> {
+    let mut groups: Vec<(Vec<GroupValue>, Vec<Accumulator>)> = vec![];
+    let mut group_map: FxHashMap<Vec<GroupValue>, usize> = FxHashMap::default();
+    if group_by.is_empty() {
+      groups.push((vec![], aggregates.iter().map(Accumulator::new).collect()));
+      group_map.insert(vec![], 0);
+    }
+    self.scan_rows(name, filter, |db, row| {
+      let mut group = Vec::with_capacity(group_by.len());
+      for key in group_by {
+        group.push(GroupValue::from(db.query_key(name, row, key)?));
+      }
+      let idx = match group_map.get(&group) {
+        Some(&idx) => idx,
+        None => {
+          groups.push((group.clone(), aggregates.iter().map(Accumulator::new).collect()));
+          group_map.insert(group, groups.len() - 1);
+          groups.len() - 1
+        },
+      };
+      for (aggregate, accumulator) in aggregates.iter().zip(&mut groups[idx].1) {
+        let value = match aggregate.key() {
+          Some(key) => Some(db.query_key(name, row, key)?),
+          None => None,
+        };
+        accumulator.add(value)?;
+      }
+      Ok(true)
+    })?;
+
+    let names: Vec<String> = group_by.iter().map(key_name).chain(aggregates.iter().map(DbAggregate::name)).collect();
+    let mut results = vec![];
+    for (group, accumulators) in groups {
+      let result: Vec<DbRowColumnValue> = group
+        .into_iter()
+        .map(DbRowColumnValue::from)
+        .chain(accumulators.into_iter().map(Accumulator::finish))
+        .collect();
+      if let Some(having) = having {
+        let matches = having.evaluate(&mut |key| {
+          let Some(idx) = names.iter().position(|name| *name == key_name(key)) else {
+            bail!("`{}` is neither a group_by key nor an aggregate", key_name(key));
+          };
+          Ok(result[idx].clone())
+        })?;
+        if matches != Some(true) {
+          continue
+        }
+      }
+      results.push(result);
+    }
+    Ok(results)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::{json, Value};
+  use crate::testing::{MemoryDb, memory_db, request, rows};
+
+  fn sales() -> MemoryDb {
+    let mut db = memory_db();
+    request(&mut db, json!([
+      {"type": "TableCreate", "name": "sale", "columns": [
+        {"name": "shop", "type": {"Text": 8}},
+        {"name": "amount", "type": "Signed32", "nullable": true},
+        {"name": "price", "type": "Float64"},
+      ]},
+      {"type": "TableInsert", "name": "sale", "columns": ["b", 3, 1.5]},
+      {"type": "TableInsert", "name": "sale", "columns": ["a", 2, 2.0]},
+      {"type": "TableInsert", "name": "sale", "columns": ["b", null, 0.5]},
+      {"type": "TableInsert", "name": "sale", "columns": ["b", -1, 1.0]},
+    ])).unwrap();
+    db
+  }
+
+  fn aggregate(db: &mut MemoryDb, query: Value) -> Vec<Value> {
+    let mut query = query;
+    query["type"] = json!("TableAggregate");
+    query["name"] = json!("sale");
+    rows(&request(db, json!([query])).unwrap()[0])
+  }
+
+  #[test]
+  fn groups_in_the_order_they_were_first_seen() {
+    let mut db = sales();
+    let result = aggregate(&mut db, json!({
+      "group_by": ["shop"],
+      "aggregates": [{"Count": null}, {"Count": "amount"}, {"Sum": "amount"}, {"Min": "amount"}, {"Max": "price"}, {"Avg": "amount"}],
+    }));
+    assert_eq!(result, [json!(["b", 3, 2, 2, -1, 1.5, 1.0]), json!(["a", 1, 1, 2, 2, 2.0, 2.0])]);
+  }
+
+  #[test]
+  fn sums_of_integers_stay_integers() {
+    let mut db = sales();
+    let result = aggregate(&mut db, json!({"aggregates": [{"Sum": "amount"}, {"Sum": "price"}]}));
+    assert_eq!(result, [json!([4, 5.0])]);
+  }
+
+  #[test]
+  fn empty_input_is_a_single_group_without_group_by() {
+    let mut db = sales();
+    let filter = json!({"Eq": ["shop", "c"]});
+    let result = aggregate(&mut db, json!({"where": filter, "aggregates": [{"Count": null}, {"Sum": "amount"}, {"Avg": "price"}]}));
+    assert_eq!(result, [json!([0, null, null])]);
+    let result = aggregate(&mut db, json!({"where": filter, "group_by": ["shop"], "aggregates": [{"Count": null}]}));
+    assert!(result.is_empty());
+  }
+
+  #[test]
+  fn having_filters_groups() {
+    let mut db = sales();
+    let result = aggregate(&mut db, json!({
+      "group_by": ["shop"],
+      "aggregates": [{"Count": null}, {"Sum": "amount"}],
+      "having": {"And": [{"Gt": ["count", 1]}, {"Eq": ["sum(amount)", 2]}]},
+    }));
+    assert_eq!(result, [json!(["b", 3, 2])]);
+    let err = request(&mut db, json!([{"type": "TableAggregate", "name": "sale", "aggregates": [{"Count": null}], "having": {"Gt": ["price", 1]}}])).unwrap_err();
+    assert!(err.to_string().contains("neither a group_by key nor an aggregate"), "{err}");
+  }
+
+  #[test]
+  fn sums_need_numbers() {
+    let mut db = sales();
+    let err = request(&mut db, json!([{"type": "TableAggregate", "name": "sale", "aggregates": [{"Sum": "shop"}]}])).unwrap_err();
+    assert!(err.to_string().contains("SUM needs numbers"), "{err}");
+  }
+}
@@ -0,0 +1,486 @@
+//! integrity verifier (fsck)\
+//! walks the header, the shape and all tables looking for inconsistencies, and optionally fixes them
+
+use std::fmt;
+use rustc_hash::{FxHashMap, FxHashSet};
+use anyhow::Result;
+use crate::{
+  database::{Database, RwData},
+  types::Type,
+  shape::{DROPPED_TABLE, MAX_ROW_SIZE},
+  heap::HeapRef,
+};
+
+/// What a sector is used for
+#[derive(Clone, PartialEq, Eq)]
+enum Owner {
+  Header,
+  Shape,
+  Table(String),
+  /// values of heap columns of the table
+  Heap(String),
+  /// small heap values, of any table
+  HeapPage,
+}
+
+impl Owner {
+  /// Whether the sector is a data sector (and should have a checksum)
+  fn is_data(&self) -> bool {
+    matches!(self, Owner::Table(_) | Owner::Heap(_) | Owner::HeapPage)
+  }
+}
+
+impl fmt::Display for Owner {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Owner::Header => f.write_str("the header"),
+      Owner::Shape => f.write_str("the shape"),
+      Owner::Table(name) => write!(f, "table `{name}`"),
+      Owner::Heap(name) => write!(f, "the heap of table `{name}`"),
+      Owner::HeapPage => f.write_str("a heap page"),
+    }
+  }
+}
+
+pub struct Issue {
+  pub description: String,
+  /// whether `--repair` can fix it
+  pub repairable: bool,
+}
+
+impl fmt::Display for Issue {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(&self.description)
+  }
+}
+
+#[derive(Default)]
+pub struct CheckReport {
+  pub issues: Vec<Issue>,
+  /// `true` if the repairable issues were fixed (the shape still needs to be synced)
+  pub repaired: bool,
+}
+
+impl CheckReport {
+  fn issue(&mut self, repairable: bool, description: String) {
+    self.issues.push(Issue { description, repairable });
+  }
+}
+
+impl<T: RwData> Database<T> {
+  /// Check the database for inconsistencies\
+  /// With `repair`, the shape is rebuilt to fix everything that can be fixed (remember to call `sync_database` afterwards)
+  pub fn check(&mut self, repair: bool) -> Result<CheckReport> {
+    let mut report = CheckReport { repaired: repair, ..Default::default() };
+    //opening the database for a repair replays the journal, so this only shows up when checking
+    if let Some(sectors) = self.pending_journal()? {
+      report.issue(true, format!("the journal contains a committed batch of {sectors} sectors that wasn't applied yet"));
+    }
+    let sector_count = self.header.sector_count;
+    let sector_size = self.sector_size();
+
+    //who uses every sector
+    let mut owners: FxHashMap<u64, Owner> = FxHashMap::default();
+    owners.insert(0, Owner::Header);
+    let (shape_start, shape_end) = self.header.shape_location;
+    if shape_end > sector_count {
+      report.issue(false, format!("shape sectors {shape_start}..{shape_end} are past the sector count ({sector_count})"));
+    }
+    for sector in shape_start..shape_end {
+      owners.insert(sector, Owner::Shape);
+    }
+
+    //table map
+    let table_count = self.shape.tables.len();
+    let mut table_map_broken = false;
+    for (name, &idx) in &self.shape.table_map {
+      if self.shape.tables.get(idx).is_none_or(|table| table.name != *name) {
+        report.issue(true, format!("table_map entry `{name}` points at a wrong index ({idx})"));
+        table_map_broken = true;
+      }
+    }
+    let mut seen_names = FxHashSet::default();
+    for (idx, table) in self.shape.tables.iter_mut().enumerate() {
+      if !seen_names.insert(table.name.clone()) {
+        report.issue(true, format!("table name `{}` is used by more than one table", table.name));
+        table_map_broken = true;
+        if repair {
+          table.name = format!("{}_{idx}", table.name);
+        }
+      } else if self.shape.table_map.get(&table.name) != Some(&idx) {
+        report.issue(true, format!("table `{}` is missing from table_map", table.name));
+        table_map_broken = true;
+      }
+    }
+    if repair && table_map_broken {
+      self.shape.table_map = self.shape.tables
+        .iter()
+        .enumerate()
+        .map(|(idx, table)| (table.name.clone(), idx))
+        .collect();
+    }
+
+    for table in &mut self.shape.tables {
+      let name = table.name.clone();
+
+      //column map
+      let mut named = vec![false; table.columns.len()];
+      let mut bad_columns = vec![];
+      for (column_name, &idx) in &table.column_map {
+        match named.get_mut(idx) {
+          Some(false) => named[idx] = true,
+          Some(true) => {
+            report.issue(true, format!("table `{name}`: column_map entry `{column_name}` points at an already named column ({idx})"));
+            bad_columns.push(column_name.clone());
+          },
+          None => {
+            report.issue(true, format!("table `{name}`: column_map entry `{column_name}` points at a wrong index ({idx})"));
+            bad_columns.push(column_name.clone());
+          },
+        }
+      }
+      for column_name in bad_columns.iter().filter(|_| repair) {
+        table.column_map.remove(column_name);
+      }
+      for (idx, _) in named.iter().enumerate().filter(|(_, named)| !**named) {
+        report.issue(true, format!("table `{name}`: column {idx} has no name"));
+        if repair {
+          table.column_map.insert(format!("column_{idx}"), idx);
+        }
+      }
+
+      //pointers
+      for (idx, column) in table.columns.iter().enumerate() {
+        if let Type::Pointer(target) = column.typ {
+          if target != DROPPED_TABLE && target as usize >= table_count {
+            report.issue(false, format!("table `{name}`: column {idx} points at a table that doesn't exist ({target})"));
+          }
+        }
+      }
+
+      //fragments
+      //a size that overflows is just as invalid as a huge one
+      let row_size = table.checked_byte_size().unwrap_or(usize::MAX);
+      if row_size == 0 || row_size > MAX_ROW_SIZE {
+        report.issue(false, format!("table `{name}`: invalid row size ({row_size})"));
+        continue
+      }
+      let sectors_per_fragment = table.sectors_per_fragment(sector_size);
+      let rows_per_fragment = table.rows_per_fragment(sector_size) as u64;
+      if table.deleted.extents().last().is_some_and(|extent| extent.end > table.row_count) {
+        report.issue(true, format!("table `{name}`: rows past row_count ({}) are marked as deleted", table.row_count));
+        if repair {
+          table.deleted.truncate(table.row_count);
+        }
+      }
+      //fragments without any rows left are given back, all of their sectors are 0
+      let given_back = |fragment: usize| {
+        let start = fragment * sectors_per_fragment;
+        let end = (start + sectors_per_fragment).min(table.fragmentation.len());
+        table.fragmentation[start..end].iter().all(|&sector| sector == 0)
+          && table.deleted.contains_range(table.fragment_rows(fragment, sector_size))
+      };
+      let mut valid_sectors = table.fragmentation.len();
+      for (idx, &sector) in table.fragmentation.iter().enumerate() {
+        let fragment = idx / sectors_per_fragment;
+        if sector == 0 && given_back(fragment) {
+          continue
+        } else if sector == 0 || sector >= sector_count {
+          report.issue(true, format!("table `{name}`: fragment {fragment} is in an invalid sector ({sector})"));
+        } else if let Some(owner) = owners.get(&sector) {
+          report.issue(true, format!("table `{name}`: fragment {fragment} is in sector {sector}, which is already used by {owner}"));
+        } else {
+          owners.insert(sector, Owner::Table(name.clone()));
+          continue
+        }
+        valid_sectors = valid_sectors.min(idx);
+      }
+      //a fragment is only usable if all of its sectors are
+      let valid_fragments = valid_sectors / sectors_per_fragment;
+      let capacity = valid_fragments as u64 * rows_per_fragment;
+      let needed_fragments = table.row_count.div_ceil(rows_per_fragment) as usize;
+      let fragment_count = table.fragmentation.len().div_ceil(sectors_per_fragment);
+      if table.row_count > capacity {
+        report.issue(true, format!(
+          "table `{name}`: row_count ({}) doesn't fit in {valid_fragments} valid fragments ({capacity} rows)",
+          table.row_count
+        ));
+      } else if needed_fragments < fragment_count {
+        report.issue(true, format!(
+          "table `{name}`: has {fragment_count} fragments, but only {needed_fragments} are needed for {} rows",
+          table.row_count
+        ));
+      } else if table.fragmentation.len() % sectors_per_fragment != 0 {
+        report.issue(true, format!(
+          "table `{name}`: fragments have {sectors_per_fragment} sectors, but there are {} sectors in total",
+          table.fragmentation.len()
+        ));
+      }
+      if repair {
+        //rows in the dropped fragments are lost, but everything before them stays consistent
+        let keep = valid_fragments.min(needed_fragments) * sectors_per_fragment;
+        for sector in &table.fragmentation[keep..] {
+          if owners.get(sector).is_some_and(|owner| *owner == Owner::Table(name.clone())) {
+            owners.remove(sector);
+          }
+        }
+        table.fragmentation.truncate(keep);
+        table.row_count = table.row_count.min(capacity);
+        table.deleted.truncate(table.row_count);
+      }
+    }
+
+    //heap values
+    //slots of heap pages used by the rows
+    let mut used_slots: FxHashMap<u64, FxHashSet<u32>> = FxHashMap::default();
+    for table_idx in 0..self.shape.tables.len() {
+      let table = &self.shape.tables[table_idx];
+      let name = table.name.clone();
+      //tables with invalid rows or that can't be looked up by name were reported already
+      let row_size = table.checked_byte_size().unwrap_or(usize::MAX);
+      if row_size == 0 || row_size > MAX_ROW_SIZE || self.shape.table_map.get(&name) != Some(&table_idx) {
+        continue
+      }
+      let readable_rows = (table.fragmentation.len() / table.sectors_per_fragment(sector_size)) as u64
+        * table.rows_per_fragment(sector_size) as u64;
+      let heap_columns: Vec<usize> = table.columns
+        .iter()
+        .enumerate()
+        .filter(|(_, column)| column.typ.is_heap())
+        .map(|(idx, _)| idx)
+        .collect();
+      let rows: Vec<u64> = (0..table.row_count.min(readable_rows)).filter(|&row| !table.is_deleted(row)).collect();
+      for row in rows {
+        for &column in &heap_columns {
+          //unreadable rows are reported with the checksums
+          let Ok(data) = self.table_read_row_column(&name, row, column) else {
+            continue
+          };
+          let heap_ref = HeapRef::from_bytes(&data);
+          let mut broken = false;
+          if heap_ref.is_packed() {
+            let (sector, slot) = (heap_ref.sector, heap_ref.slot - 1);
+            match (owners.get(&sector), self.heap_read(heap_ref)) {
+              (Some(owner), _) if *owner != Owner::HeapPage => {
+                report.issue(true, format!("table `{name}`: heap value in row {row}, column {column} uses sector {sector}, which is already used by {owner}"));
+                broken = true;
+              },
+              (_, Err(err)) => {
+                report.issue(true, format!("table `{name}`: heap value in row {row}, column {column} is broken: {err}"));
+                broken = true;
+              },
+              (_, Ok(_)) => if used_slots.entry(sector).or_default().insert(slot) {
+                owners.insert(sector, Owner::HeapPage);
+              } else {
+                report.issue(true, format!("table `{name}`: heap value in row {row}, column {column} uses slot {slot} of heap page {sector}, which is already used by another value"));
+                broken = true;
+              },
+            }
+          } else {
+            match self.heap_sectors(heap_ref) {
+              Ok(sectors) => for sector in sectors {
+                if let Some(owner) = owners.get(&sector) {
+                  report.issue(true, format!("table `{name}`: heap value in row {row}, column {column} uses sector {sector}, which is already used by {owner}"));
+                  broken = true;
+                  break
+                }
+                owners.insert(sector, Owner::Heap(name.clone()));
+              },
+              Err(err) => {
+                report.issue(true, format!("table `{name}`: heap value in row {row}, column {column} is broken: {err}"));
+                broken = true;
+              }
+            }
+          }
+          if repair && broken {
+            //the value is lost, sectors it used to own are freed below
+            self.table_write_row_column(&name, row, column, &HeapRef::default().to_bytes())?;
+          }
+        }
+      }
+    }
+
+    //heap pages
+    let heap_pages: Vec<(u64, u32)> = self.shape.heap_pages.iter().map(|(&sector, &free)| (sector, free)).collect();
+    for (sector, free) in heap_pages {
+      if owners.get(&sector) != Some(&Owner::HeapPage) {
+        match owners.get(&sector) {
+          Some(owner) => report.issue(true, format!("heap page {sector} is used by {owner}")),
+          None => report.issue(true, format!("heap page {sector} doesn't contain any values")),
+        }
+        if repair {
+          //it's freed below if nothing else uses it
+          self.shape.heap_pages.remove(&sector);
+        }
+        continue
+      }
+      let mut page = self.heap_page(sector)?;
+      let used = used_slots.remove(&sector).unwrap_or_default();
+      let unused: Vec<u32> = page.used_slots().filter(|slot| !used.contains(slot)).collect();
+      for &slot in &unused {
+        report.issue(true, format!("slot {slot} of heap page {sector} is not used by any row"));
+      }
+      if page.free_bytes() != free {
+        report.issue(true, format!("heap page {sector} has {} free bytes, but {free} are recorded", page.free_bytes()));
+      }
+      if repair && (!unused.is_empty() || page.free_bytes() != free) {
+        //there's at least one used slot, so the page doesn't become empty
+        for &slot in &unused {
+          page.remove(slot);
+        }
+        self.shape.heap_pages.insert(sector, page.free_bytes());
+        if !unused.is_empty() {
+          self.write_sector(sector, &page.into_bytes(), 0)?;
+        }
+      }
+    }
+
+    //free sectors
+    let mut free_broken = false;
+    for sector in self.shape.free.sectors() {
+      if sector == 0 || sector >= sector_count {
+        report.issue(true, format!("free sector {sector} is invalid"));
+      } else if let Some(owner) = owners.get(&sector) {
+        report.issue(true, format!("free sector {sector} is still used by {owner}"));
+      } else {
+        continue
+      }
+      free_broken = true;
+    }
+    for sector in 1..sector_count {
+      if !owners.contains_key(&sector) && !self.shape.free.contains(sector) {
+        report.issue(true, format!("sector {sector} is not used by anything, but it's not free either"));
+        free_broken = true;
+      }
+    }
+    if self.shape.free.extents().last().is_some_and(|extent| extent.end == sector_count) {
+      report.issue(true, "free sectors at the end of the file were not given back".into());
+      free_broken = true;
+    }
+    if repair && free_broken {
+      //rebuild the free map from scratch, and give the free tail back
+      self.shape.free.clear();
+      for sector in (1..sector_count).filter(|sector| !owners.contains_key(sector)) {
+        self.shape.free.free(sector);
+      }
+      if let Some(start) = self.shape.free.take_tail(sector_count) {
+        self.header.sector_count = start;
+        self.mark_header_dirty();
+      }
+    }
+
+    //checksum trailers
+    let mut data_sectors: Vec<u64> = owners
+      .iter()
+      .filter(|(_, owner)| owner.is_data())
+      .map(|(&sector, _)| sector)
+      .collect();
+    data_sectors.sort_unstable();
+    for sector in data_sectors {
+      if let Err(err) = self.read_sector(sector) {
+        report.issue(false, format!("{err}"));
+      }
+    }
+
+    if repair && report.issues.iter().any(|issue| issue.repairable) {
+      self.mark_shape_dirty();
+    }
+    Ok(report)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::Cursor;
+  use serde_json::json;
+  use crate::{
+    database::{Database, DEFAULT_SECTOR_SIZE},
+    journal::{self, JournalRecord},
+    testing::{memory_db, request},
+  };
+
+  #[test]
+  fn reports_pending_journal_without_replaying_it() {
+    let mut db = memory_db();
+    request(&mut db, json!([
+      {"type": "TableCreate", "name": "a", "columns": [{"name": "x", "type": {"Text": 8}}]},
+      {"type": "TableInsert", "name": "a", "columns": ["first"]},
+    ])).unwrap();
+    let (data, _) = db.into_files();
+    let image = data.get_ref().clone();
+    let mut journal = Cursor::new(vec![]);
+    journal::write_journal(&mut journal, &JournalRecord { sectors: vec![(1, vec![0xff; DEFAULT_SECTOR_SIZE])] }).unwrap();
+
+    let mut db = Database::with_journal(data, journal).unwrap();
+    db.read_database_without_replay().unwrap();
+    let report = db.check(false).unwrap();
+    assert_eq!(report.issues.len(), 1);
+    assert!(report.issues[0].description.contains("journal"));
+    let (data, journal) = db.into_files();
+    assert_eq!(*data.get_ref(), image);
+    assert!(!journal.unwrap().get_ref().is_empty());
+  }
+
+  #[test]
+  fn accepts_pointers_to_dropped_tables() {
+    let mut db = memory_db();
+    request(&mut db, json!([
+      {"type": "TableCreate", "name": "a", "columns": [{"name": "x", "type": {"Text": 8}}]},
+      {"type": "TableCreate", "name": "b", "columns": [{"name": "p", "type": {"Pointer": "a"}, "nullable": true}]},
+    ])).unwrap();
+    request(&mut db, json!([{"type": "TableDelete", "name": "a"}])).unwrap();
+    assert!(db.check(false).unwrap().issues.is_empty());
+  }
+
+  #[test]
+  fn clean_database_has_no_issues() {
+    let mut db = memory_db();
+    request(&mut db, json!([
+      {"type": "TableCreate", "name": "a", "columns": [{"name": "x", "type": {"Text": 8}}]},
+      {"type": "TableCreate", "name": "b", "columns": [{"name": "x", "type": {"Text": 600}}]},
+      {"type": "TableInsert", "name": "a", "columns": ["hello"]},
+      {"type": "TableInsert", "name": "b", "columns": ["world"]},
+      {"type": "TableInsert", "name": "b", "columns": ["again"]},
+    ])).unwrap();
+    request(&mut db, json!([{"type": "TableDelete", "name": "a"}])).unwrap();
+    let issues: Vec<String> = db.check(false).unwrap().issues.iter().map(|i| i.to_string()).collect();
+    assert!(issues.is_empty(), "{issues:?}");
+  }
+
+  #[test]
+  fn reports_checksum_mismatches() {
+    let mut db = memory_db();
+    request(&mut db, json!([
+      {"type": "TableCreate", "name": "a", "columns": [{"name": "x", "type": {"Text": 8}}]},
+      {"type": "TableInsert", "name": "a", "columns": ["first"]},
+    ])).unwrap();
+    let sector = db.shape.tables[0].fragmentation[0];
+    let (mut data, journal) = db.into_files();
+    data.get_mut()[sector as usize * DEFAULT_SECTOR_SIZE + 5] ^= 1;
+
+    let mut db = Database::with_journal(data, journal.unwrap()).unwrap();
+    db.read_database().unwrap();
+    let report = db.check(true).unwrap();
+    assert!(report.issues.iter().any(|issue| !issue.repairable && issue.description.contains("checksum mismatch")));
+  }
+
+  #[test]
+  fn repairs_a_broken_shape() {
+    let mut db = memory_db();
+    request(&mut db, json!([
+      {"type": "TableCreate", "name": "a", "columns": [{"name": "x", "type": {"Text": 600}}]},
+      {"type": "TableInsert", "name": "a", "columns": ["first"]},
+      {"type": "TableInsert", "name": "a", "columns": ["second"]},
+    ])).unwrap();
+    //the second fragment is lost, and the first one is reclaimed as well
+    let table = &mut db.shape.tables[0];
+    let lost = table.fragmentation.pop().unwrap();
+    db.shape.free.free(db.shape.tables[0].fragmentation[0]);
+
+    let report = db.check(true).unwrap();
+    assert!(report.issues.iter().all(|issue| issue.repairable));
+    assert_eq!(db.shape.tables[0].row_count, 1);
+    assert!(db.shape.free.contains(lost));
+    assert!(db.check(false).unwrap().issues.is_empty());
+  }
+}
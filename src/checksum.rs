@@ -0,0 +1,52 @@
+//! corruption detection
+
+use std::fmt;
+
+pub fn checksum(data: &[u8]) -> u32 {
+  crc32c::crc32c(data)
+}
+
+/// Stored checksum doesn't match the data\
+/// Returned (through `anyhow`) whenever corruption is detected, so it can be told apart from other errors
+#[derive(Debug)]
+pub struct CorruptionError {
+  pub what: String,
+}
+
+impl fmt::Display for CorruptionError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "database is corrupted: checksum mismatch in {}", self.what)
+  }
+}
+
+impl std::error::Error for CorruptionError {}
+
+pub fn verify(data: &[u8], expected: u32, what: impl FnOnce() -> String) -> Result<(), CorruptionError> {
+  if checksum(data) == expected {
+    Ok(())
+  } else {
+    Err(CorruptionError { what: what() })
+  }
+}
+
+/// Every sector except the header one ends with the checksum of the rest of it
+pub const TRAILER_SIZE: usize = 4;
+
+/// Sector as it's stored in the file: the data followed by its checksum
+pub fn seal(data: &[u8]) -> Vec<u8> {
+  let mut sector = Vec::with_capacity(data.len() + TRAILER_SIZE);
+  sector.extend_from_slice(data);
+  sector.extend_from_slice(&checksum(data).to_le_bytes());
+  sector
+}
+
+/// Check the trailer of a stored sector and strip it\
+/// A sector that's all zeroes was never written (it's past the end of the file, or was only allocated), so it isn't checked
+pub fn unseal(sector: &[u8], what: impl FnOnce() -> String) -> Result<&[u8], CorruptionError> {
+  let (data, trailer) = sector.split_at(sector.len() - TRAILER_SIZE);
+  if sector.iter().all(|&byte| byte == 0) {
+    return Ok(data)
+  }
+  verify(data, u32::from_le_bytes(trailer.try_into().unwrap()), what)?;
+  Ok(data)
+}
@@ -1,38 +1,154 @@
 use std::{
-  io::{Read, Write, Seek, SeekFrom},
+  io::{self, Read, Write, Seek, SeekFrom, Cursor},
   ops::Range,
-  iter::repeat,
+  iter::repeat_n,
   fs::File,
 };
-use anyhow::{Result, ensure};
+use anyhow::{Result, Context, ensure, bail};
 use divrem::DivCeil;
-use crate::{shape::DbShape, types::ReprSize, header::DbHeader};
+use rustc_hash::FxHashMap;
+use crate::{
+  shape::DbShape,
+  types::ReprSize,
+  header::{self, DbHeader, MAGIC, HEADER_SLOT_SIZE},
+  checksum,
+  journal::{self, JournalRecord},
+  transaction::{Transactions, TransactionId},
+  heap::HeapRef,
+};
+
+/// Used for new databases unless `create --sector-size` says otherwise\
+/// The actual sector size of a database is stored in its header
+pub const DEFAULT_SECTOR_SIZE: usize = 1024;
+
+pub trait RwData: Read + Write + Seek {
+  /// Make sure that everything written so far actually reached the storage
+  fn sync(&mut self) -> io::Result<()> {
+    self.flush()
+  }
+
+  /// Grow or shrink the underlying storage
+  fn set_size(&mut self, len: u64) -> io::Result<()>;
+}
+
+impl RwData for File {
+  fn sync(&mut self) -> io::Result<()> {
+    self.sync_all()
+  }
 
-//pub const SECTOR_SIZE: usize = 128 * 1024 * 1024;
-pub const SECTOR_SIZE: usize = 1024;
+  fn set_size(&mut self, len: u64) -> io::Result<()> {
+    self.set_len(len)
+  }
+}
 
-pub trait RwData: Read + Write + Seek {}
-impl<T: Read + Write + Seek> RwData for T {}
+impl RwData for Cursor<Vec<u8>> {
+  fn set_size(&mut self, len: u64) -> io::Result<()> {
+    self.get_mut().resize(len as usize, 0);
+    Ok(())
+  }
+}
+
+/// In-memory state of the database that's not committed to the disk yet\
+/// Restoring it throws away everything that happened after it was taken
+#[derive(Clone, Default)]
+pub struct Snapshot {
+  header: DbHeader,
+  shape: DbShape,
+  header_dirty: bool,
+  shape_dirty: bool,
+  pending: FxHashMap<u64, Box<[u8]>>,
+}
 
 pub struct Database<T: RwData> {
   data: T,
+  journal: Option<T>,
   pub header: DbHeader,
   pub shape: DbShape,
   header_dirty: bool,
   shape_dirty: bool,
+  /// sectors modified since the last commit\
+  /// these only reach `data` (through the journal) in `sync_database`
+  pending: FxHashMap<u64, Box<[u8]>>,
+  pub transactions: Transactions,
+  /// transaction the current state belongs to (while a request works on it)\
+  /// it reads the sectors preserved for it instead of the ones the main state overwrote since it began
+  pub active_transaction: Option<TransactionId>,
 }
 
 impl<T: RwData> Database<T> {
   pub fn new(data: T) -> Result<Self> {
     Ok(Self {
       data,
+      journal: None,
       header: DbHeader::default(),
       shape: DbShape::default(),
       header_dirty: true,
       shape_dirty: true,
+      pending: FxHashMap::default(),
+      transactions: Transactions::default(),
+      active_transaction: None,
     })
   }
 
+  /// Same as `new`, but all writes go through a write-ahead log first
+  pub fn with_journal(data: T, journal: T) -> Result<Self> {
+    let mut db = Self::new(data)?;
+    db.journal = Some(journal);
+    Ok(db)
+  }
+
+  pub fn snapshot(&self) -> Snapshot {
+    Snapshot {
+      header: self.header,
+      shape: self.shape.clone(),
+      header_dirty: self.header_dirty,
+      shape_dirty: self.shape_dirty,
+      pending: self.pending.clone(),
+    }
+  }
+
+  pub fn restore(&mut self, snapshot: Snapshot) {
+    self.header = snapshot.header;
+    self.shape = snapshot.shape;
+    self.header_dirty = snapshot.header_dirty;
+    self.shape_dirty = snapshot.shape_dirty;
+    self.pending = snapshot.pending;
+  }
+
+  /// Like `restore`, but returns the state that was replaced instead of dropping it
+  pub fn replace_state(&mut self, snapshot: Snapshot) -> Snapshot {
+    Snapshot {
+      header: std::mem::replace(&mut self.header, snapshot.header),
+      shape: std::mem::replace(&mut self.shape, snapshot.shape),
+      header_dirty: std::mem::replace(&mut self.header_dirty, snapshot.header_dirty),
+      shape_dirty: std::mem::replace(&mut self.shape_dirty, snapshot.shape_dirty),
+      pending: std::mem::replace(&mut self.pending, snapshot.pending),
+    }
+  }
+
+  /// The database file and the journal, without committing anything
+  pub fn into_files(self) -> (T, Option<T>) {
+    (self.data, self.journal)
+  }
+
+  /// Space for data in every sector except the header one, the rest is taken by the checksum trailer\
+  /// Sectors in the file are `header.sector_size` bytes long
+  pub fn sector_size(&self) -> usize {
+    self.header.sector_size as usize - checksum::TRAILER_SIZE
+  }
+
+  /// Can only be changed before anything is written to the database
+  pub fn set_sector_size(&mut self, sector_size: usize) -> Result<()> {
+    header::validate_sector_size(sector_size)?;
+    ensure!(
+      self.header.sector_count == 1 && self.shape.tables.is_empty(),
+      "sector size can't be changed after the database was created"
+    );
+    self.header.sector_size = sector_size as u32;
+    self.header_dirty = true;
+    Ok(())
+  }
+
   pub(crate) fn mark_shape_dirty(&mut self) {
     self.shape_dirty = true;
   }
@@ -41,117 +157,188 @@ impl<T: RwData> Database<T> {
     self.header_dirty = true;
   }
 
+  /// Reads the sector, including any changes that are not committed yet\
+  /// Sectors that were allocated but never written (past the end of the file) read as zeroes\
+  /// The checksum trailer is verified and stripped, so this returns `sector_size()` bytes (except for the header sector)
   pub fn read_sector(&mut self, sector: u64) -> Result<Box<[u8]>> {
-    let mut buffer = vec![0; SECTOR_SIZE].into_boxed_slice();
-    self.data.seek(SeekFrom::Start(sector * SECTOR_SIZE as u64))?;
-    self.data.read_exact(&mut buffer[..])?;
+    if let Some(buffer) = self.pending.get(&sector) {
+      return Ok(buffer.clone())
+    }
+    let preserved = self.active_transaction.and_then(|id| self.transactions.preserved(id, sector));
+    let buffer = match preserved {
+      Some(data) => data.into(),
+      None => self.read_committed(sector)?,
+    };
+    if sector == 0 {
+      return Ok(buffer)
+    }
+    Ok(checksum::unseal(&buffer, || format!("sector {sector}"))?.into())
+  }
+
+  /// Contents of the sector in the database file (including the trailer), without checking them
+  fn read_committed(&mut self, sector: u64) -> Result<Box<[u8]>> {
+    let sector_size = self.header.sector_size as usize;
+    let mut buffer = vec![0; sector_size].into_boxed_slice();
+    let sector_start = sector * sector_size as u64;
+    let data_len = self.data.seek(SeekFrom::End(0))?;
+    if sector_start < data_len {
+      let available = (data_len - sector_start).min(sector_size as u64) as usize;
+      self.data.seek(SeekFrom::Start(sector_start))?;
+      self.data.read_exact(&mut buffer[..available])?;
+    }
     Ok(buffer)
   }
 
+  /// Open transactions still see the database as it was when they began, with their own shape\
+  /// so committed sectors they can see are kept for them before they're overwritten (or cut off by `truncate`)\
+  /// The header is not needed, transactions have their own copy of it
+  fn preserve_for_transactions(&mut self, sectors: impl Iterator<Item = u64>) -> Result<()> {
+    self.transactions.expire();
+    if self.transactions.is_empty() {
+      return Ok(())
+    }
+    for sector in sectors.filter(|&sector| sector != 0) {
+      let data = self.read_committed(sector)?;
+      self.transactions.preserve(sector, &data);
+    }
+    Ok(())
+  }
+
+  /// Data is buffered in memory until the next `sync_database`
   pub fn write_sector(&mut self, sector: u64, data: &[u8], offset: usize) -> Result<()> {
     ensure!(sector < self.header.sector_count, "Unallocated sector");
-    ensure!((data.len() + offset) <= SECTOR_SIZE, "Data does not fit inside the sector");
-
-    //write data
-    self.data.seek(SeekFrom::Start(offset as u64 + sector * SECTOR_SIZE as u64))?;
-    self.data.write_all(data)?;
+    //the header sector has no trailer
+    let size = if sector == 0 { self.header.sector_size as usize } else { self.sector_size() };
+    ensure!((data.len() + offset) <= size, "Data does not fit inside the sector");
 
-    //if writing a non-sector-sized buffer a new sector...
-    //...seek to the last byte and write something to ensure valid file size
-    if ((data.len() + offset) < SECTOR_SIZE) && (sector >= self.header.sector_count) {
-      self.data.seek(SeekFrom::Start((sector + 1) * SECTOR_SIZE as u64 - 1))?;
-      self.data.write_all(&[0])?;
-    }
+    //partial writes need the rest of the sector
+    let mut buffer = if data.len() == size {
+      vec![0; size].into_boxed_slice()
+    } else {
+      self.read_sector(sector)?
+    };
+    buffer[offset..(offset + data.len())].copy_from_slice(data);
+    self.pending.insert(sector, buffer);
 
-    //update sector count
-    //XXX: we're using allocation api now so no need for this anymore
-    // self.header.sector_count = self.header.sector_count.max(sector + 1);
-    // self.header_dirty = true;
-    
     Ok(())
   }
 
+  /// Reads both header slots and picks the newest valid one\
+  /// The sector size isn't known yet, but the slots always fit in the smallest allowed sector
   pub fn read_header(&mut self) -> Result<()> {
+    self.header.sector_size = header::MIN_SECTOR_SIZE as u32;
     let buf = self.read_sector(0)?;
-    self.header = bincode::deserialize(&buf)?;
+    let mut newest: Option<DbHeader> = None;
+    let mut error = None;
+    for slot in buf.chunks(HEADER_SLOT_SIZE).take(2) {
+      //check the magic before decoding anything, so we don't try to make sense of random files
+      if !slot.starts_with(&MAGIC) {
+        continue
+      }
+      match DbHeader::read_slot(slot) {
+        Ok(header) => if newest.is_none_or(|newest| header.sequence > newest.sequence) {
+          newest = Some(header);
+        },
+        Err(err) => { error.get_or_insert(err); },
+      }
+    }
+    match (newest, error) {
+      (Some(header), _) => self.header = header,
+      (None, Some(err)) => return Err(err),
+      (None, None) => bail!("not an awfuldb database"),
+    }
     self.header_dirty = false;
     Ok(())
   }
 
+  /// Writes the header into the older slot, so a torn write can't destroy the last good one\
+  /// Once this reaches the disk, everything written before it is committed
   pub fn write_header(&mut self) -> Result<()> {
-    let mut buf = vec![0; SECTOR_SIZE].into_boxed_slice();
+    self.header.sequence += 1;
+    self.header.checksum = self.header.compute_checksum();
+    let mut buf = vec![0; HEADER_SLOT_SIZE].into_boxed_slice();
     bincode::serialize_into(&mut buf[..], &self.header)?;
-    self.write_sector(0, &buf, 0)?;
+    let slot = (self.header.sequence % 2) as usize;
+    self.write_sector(0, &buf, slot * HEADER_SLOT_SIZE)?;
     self.header_dirty = false;
     Ok(())
   }
 
   pub fn read_shape(&mut self) -> Result<()> {
-    let shape_start_bytes = self.header.shape_location.0 * SECTOR_SIZE as u64;
     let shape_size_sectors = self.header.shape_location.1 - self.header.shape_location.0;
-    let shape_size_bytes = shape_size_sectors as usize * SECTOR_SIZE;
-    let mut buffer = vec![0; shape_size_bytes];
-    self.data.seek(SeekFrom::Start(shape_start_bytes))?;
-    self.data.read_exact(&mut buffer)?;
+    let mut buffer = Vec::with_capacity(shape_size_sectors as usize * self.sector_size());
+    for sector in self.header.shape_location.0..self.header.shape_location.1 {
+      buffer.extend_from_slice(&self.read_sector(sector)?);
+    }
+    if !buffer.is_empty() {
+      checksum::verify(&buffer, self.header.shape_checksum, || "the shape".into())?;
+    }
     self.shape = bincode::deserialize(&buffer)?;
     self.shape_dirty = false;
     Ok(())
   }
   
+  /// The shape is never overwritten in place, it's written to newly allocated sectors instead\
+  /// The header on the disk still points to the old shape, so it has to stay intact until the new header is written
   pub fn write_shape(&mut self) -> Result<()> {
-    let mut shape_size_sectors = self.header.shape_location.1 - self.header.shape_location.0;
-    let mut shape_size_bytes = shape_size_sectors as usize * SECTOR_SIZE;
-    
-    let mut buffer = bincode::serialize(&self.shape)?;
-
-    if buffer.len() > shape_size_bytes {
-      //TODO check if already on the edge
-      //buffer too large, we need to move the shape!
-      //Mark shape as NOT dirty NOW so we can watch for changes
-      self.shape_dirty = false;
-      for sec in (self.header.shape_location.0..self.header.shape_location.1).rev() {
-        self.reclaim_sector(sec);
+    let sector_size = self.sector_size();
+    let old_location = self.header.shape_location;
+    let mut len = DivCeil::div_ceil(bincode::serialized_size(&self.shape)?, sector_size as u64);
+
+    //The old location is only reclaimed once the new one is settled, so the new shape can never be put over it
+    let (new_location, mut buffer) = loop {
+      let free = self.shape.free.clone();
+      let sector_count = self.header.sector_count;
+      let location = self.allocate_consecutive_sectors(len);
+      for sec in (old_location.0..old_location.1).rev() {
+        self.reclaim_sector(sec)?;
       }
-      if self.shape_dirty {
-        //Re-serialize because shape changed
-        buffer = bincode::serialize(&self.shape)?;
+      //Re-serialize because shape changed, which might have made it too large for the new location
+      let buffer = bincode::serialize(&self.shape)?;
+      let needed = DivCeil::div_ceil(buffer.len() as u64, sector_size as u64);
+      if needed <= len {
+        break (location, buffer)
       }
-      let buffer_size_sectors = DivCeil::div_ceil(buffer.len() as u64, SECTOR_SIZE as u64);
-      let alloc_sec_range = self.allocate_consecutive_sectors(buffer_size_sectors);
-      self.header.shape_location = (alloc_sec_range.start, alloc_sec_range.end);
-      self.header_dirty = true;
-      shape_size_sectors = self.header.shape_location.1 - self.header.shape_location.0;
-      shape_size_bytes = shape_size_sectors as usize * SECTOR_SIZE;
-      //println!("shape_size_bytes = {shape_size_bytes}\nbuf.len = {}", buffer.len());
-    }
+      //undo both and try again with a larger location
+      self.shape.free = free;
+      self.header.sector_count = sector_count;
+      len = needed.max(len + 1);
+    };
+    self.header.shape_location = (new_location.start, new_location.end);
+    self.header_dirty = true;
+    let shape_size_bytes = (new_location.end - new_location.start) as usize * sector_size;
 
     //extend buffer to match sector len
-    buffer.extend(repeat(0).take(shape_size_bytes - buffer.len()));
-    
+    buffer.extend(repeat_n(0, shape_size_bytes - buffer.len()));
+
+    self.header.shape_checksum = checksum::checksum(&buffer);
+
     //write sector data
-    //not using write_sector because we're writing to multiple sectors at the same time!
-    let shape_start_bytes = self.header.shape_location.0 * SECTOR_SIZE as u64;
-    self.data.seek(SeekFrom::Start(shape_start_bytes))?;
-    self.data.write_all(&buffer)?;
+    for (sector, chunk) in new_location.zip(buffer.chunks(sector_size)) {
+      self.write_sector(sector, chunk, 0)?;
+    }
 
     //no longer dirty!
     self.shape_dirty = false;
-    
+
     Ok(())
   }
 
-  pub fn reclaim_sector(&mut self, sector: u64) {
-    if sector == self.header.sector_count - 1 {
-      self.header.sector_count -= 1;
+  /// Free sectors at the end of the file are given back by shrinking `sector_count`\
+  /// Sector 0 is the header, it can never be freed (a 0 in `fragmentation` means there's no sector at all)
+  pub fn reclaim_sector(&mut self, sector: u64) -> Result<()> {
+    ensure!(sector != 0, "sector 0 contains the header, it can't be freed");
+    self.shape.free.free(sector);
+    self.shape_dirty = true;
+    if let Some(start) = self.shape.free.take_tail(self.header.sector_count) {
+      self.header.sector_count = start;
       self.header_dirty = true;
-    } else {
-      self.shape.reclaim.push_back(sector);
-      self.shape_dirty = true;
     }
+    Ok(())
   }
 
   pub fn allocate_sector(&mut self) -> u64 {
-    if let Some(sector) = self.shape.reclaim.pop_front() {
+    if let Some(sector) = self.shape.free.allocate() {
       self.shape_dirty = true;
       sector
     } else {
@@ -162,42 +349,39 @@ impl<T: RwData> Database<T> {
   }
 
   pub fn allocate_multiple_sectors(&mut self, buf: &mut [u64]) {
-    if buf.is_empty() {
-      return
-    }
-    if buf.len() == 1 {
-      buf[0] = self.allocate_sector();
-    } else {
-      for entry in buf {
-        if let Some(sector) = self.shape.reclaim.pop_front() {
-          *entry = sector;
-          self.shape_dirty = true;
-          continue
-        }
-        *entry = self.header.sector_count;
-        self.header_dirty = true;
-        self.header.sector_count += 1;
-      }
+    for entry in buf {
+      *entry = self.allocate_sector();
     }
   }
 
+  /// Uses the first run of free sectors that's long enough, or grows the file
   pub fn allocate_consecutive_sectors(&mut self, len: u64) -> Range<u64> {
     if len == 0 {
-      0..0
-    } else if len == 1 {
-      let sec = self.allocate_sector();
-      sec..(sec + 1)
-    } else {
-      self.header_dirty = true;
-      self.header.sector_count += len;
-      (self.header.sector_count - len)..self.header.sector_count
+      return 0..0
+    }
+    if let Some(run) = self.shape.free.allocate_run(len) {
+      self.shape_dirty = true;
+      return run
     }
+    self.header_dirty = true;
+    self.header.sector_count += len;
+    (self.header.sector_count - len)..self.header.sector_count
   }
   
   /// Read shape and header from the drive\
   /// This is not called automatically!\
   /// You need to call it explicitly to prevent data loss\
   pub fn read_database(&mut self) -> Result<()> {
+    //Finish the last batch first if we crashed in the middle of applying it
+    self.replay_journal()?;
+    self.read_database_without_replay()?;
+    //temporary sectors left behind by a crash would otherwise show up in newly allocated sectors
+    self.truncate()
+  }
+
+  /// Like `read_database`, but a batch left in the journal is not replayed, so nothing gets written\
+  /// The database is read as it was before that batch (see `pending_journal`)
+  pub fn read_database_without_replay(&mut self) -> Result<()> {
     //Order of operations is important here!
     //Reading the shape requires shape location to be known which is located in the header
     self.read_header()?;
@@ -221,74 +405,383 @@ impl<T: RwData> Database<T> {
     if self.header_dirty {
       self.write_header()?;
     }
+    self.commit()
+  }
+
+  /// Write all pending sectors to the database file\
+  /// If there's a journal, the batch is written there first, so it's either applied in full or not at all\
+  /// Pending sectors are only dropped once the batch is applied, so a failed commit can be retried\
+  /// Checksum trailers are added here, the journal contains the sectors exactly as they're written to the file
+  fn commit(&mut self) -> Result<()> {
+    //sectors freed at the end of the file don't need to be written
+    let sector_count = self.header.sector_count;
+    self.pending.retain(|&sector, _| sector < sector_count);
+    if self.pending.is_empty() {
+      return Ok(())
+    }
+    let record = self.pending_record();
+    //the batch overwrites these, and sectors past the new end of the file are given back
+    let file_sectors = self.data.seek(SeekFrom::End(0))?.div_ceil(self.header.sector_size as u64);
+    let overwritten: Vec<u64> = record.sectors
+      .iter()
+      .map(|&(sector, _)| sector)
+      .chain(sector_count..file_sectors)
+      .collect();
+    self.preserve_for_transactions(overwritten.into_iter())?;
+    if let Some(journal) = &mut self.journal {
+      journal::write_journal(journal, &record)?;
+    }
+    self.apply_record(&record)?;
+    self.pending.clear();
+    //sectors freed at the end of the file are given back
+    self.truncate()?;
+    if let Some(journal) = &mut self.journal {
+      self.data.sync()?;
+      journal::clear_journal(journal)?;
+    }
+    Ok(())
+  }
+
+  /// Pending sectors as they're written to the file, with their checksum trailers
+  fn pending_record(&self) -> JournalRecord {
+    let mut sectors: Vec<(u64, Vec<u8>)> = self.pending
+      .iter()
+      .map(|(&sector, data)| match sector {
+        0 => (sector, data.to_vec()),
+        _ => (sector, checksum::seal(data)),
+      })
+      .collect();
+    sectors.sort_unstable_by_key(|&(sector, _)| sector);
+    JournalRecord { sectors }
+  }
+
+  /// Truncate the database file size based on the current sector count
+  pub fn truncate(&mut self) -> Result<()> {
+    let sector_len = self.header.sector_count * self.header.sector_size as u64;
+    let data_len = self.data.seek(SeekFrom::End(0))?;
+    if data_len > sector_len {
+      self.data.set_size(sector_len)?;
+    }
     Ok(())
   }
 
-  /// Defragment and optimize the database\
-  /// Currently a no-op
+  /// Temporary sectors hold data that doesn't have to survive a crash (like sort runs)\
+  /// They start past the end of both the file and the allocated sectors, so they never overlap the database\
+  /// Returns the first of them, and the current file length, which `release_temporary_sectors` goes back to
+  pub fn temporary_sectors(&mut self) -> Result<(u64, u64)> {
+    let data_len = self.data.seek(SeekFrom::End(0))?;
+    Ok((data_len.div_ceil(self.header.sector_size as u64).max(self.header.sector_count), data_len))
+  }
+
+  /// Goes straight to the file, bypassing pending sectors and the journal (but with a checksum trailer)
+  pub fn write_temporary_sector(&mut self, sector: u64, data: &[u8]) -> Result<()> {
+    ensure!(sector >= self.header.sector_count, "Temporary sector would overwrite the database");
+    ensure!(data.len() == self.sector_size(), "Data does not fill the sector");
+    self.data.seek(SeekFrom::Start(sector * self.header.sector_size as u64))?;
+    self.data.write_all(&checksum::seal(data))?;
+    Ok(())
+  }
+
+  pub fn read_temporary_sector(&mut self, sector: u64) -> Result<Box<[u8]>> {
+    ensure!(sector >= self.header.sector_count, "Not a temporary sector");
+    let buffer = self.read_committed(sector)?;
+    Ok(checksum::unseal(&buffer, || format!("temporary sector {sector}"))?.into())
+  }
+
+  /// Throw away all temporary sectors, by cutting the file back to the length `temporary_sectors` returned
+  pub fn release_temporary_sectors(&mut self, data_len: u64) -> Result<()> {
+    if self.data.seek(SeekFrom::End(0))? > data_len {
+      self.data.set_size(data_len)?;
+    }
+    Ok(())
+  }
+
+  /// The header goes last (after everything else is synced), as writing it is what commits the rest\
+  /// The journal is replayed before the header is read, so the sector size is taken from the record itself
+  fn apply_record(&mut self, record: &JournalRecord) -> Result<()> {
+    let sector_size = record.sectors.first().map_or(0, |(_, data)| data.len());
+    let mut header = None;
+    for (sector, data) in &record.sectors {
+      ensure!(data.len() == sector_size, "Invalid sector size in the journal");
+      if *sector == 0 {
+        header = Some(data);
+        continue
+      }
+      self.data.seek(SeekFrom::Start(sector * sector_size as u64))?;
+      self.data.write_all(data)?;
+    }
+    if let Some(data) = header {
+      self.data.sync()?;
+      self.data.seek(SeekFrom::Start(0))?;
+      self.data.write_all(data)?;
+    }
+    Ok(())
+  }
+
+  /// Number of sectors in a committed batch that's still in the journal (and will be replayed by `read_database`)
+  pub fn pending_journal(&mut self) -> Result<Option<usize>> {
+    let Some(journal) = &mut self.journal else {
+      return Ok(None)
+    };
+    Ok(journal::read_journal(journal)?.map(|record| record.sectors.len()))
+  }
+
+  /// Re-apply the last committed batch if it's still in the journal\
+  /// Replaying is idempotent, so it doesn't matter how much of it made it to the disk before
+  fn replay_journal(&mut self) -> Result<()> {
+    let Some(journal) = &mut self.journal else {
+      return Ok(())
+    };
+    if let Some(record) = journal::read_journal(journal)? {
+      self.apply_record(&record)?;
+      self.data.sync()?;
+    }
+    if let Some(journal) = &mut self.journal {
+      journal::clear_journal(journal)?;
+    }
+    Ok(())
+  }
+
+  /// Defragment and compact the database\
+  /// Fragments of every table are moved into consecutive sectors (right after the header, in table order),
+  /// followed by heap values and the shape, so there are no free sectors left and the file can be truncated\
+  /// Most sectors are rewritten, so this relies on the journal to be crash-safe
   pub fn optimize(&mut self) -> Result<()> {
-    //TODO database optimization
+    //read everything first, as sectors are going to be overwritten in arbitrary order
+    let mut fragments = Vec::with_capacity(self.shape.tables.len());
+    for table_idx in 0..self.shape.tables.len() {
+      let mut data = Vec::with_capacity(self.shape.tables[table_idx].fragmentation.len());
+      for fragment in 0..self.shape.tables[table_idx].fragmentation.len() {
+        let sector = self.shape.tables[table_idx].fragmentation[fragment];
+        //fragments that were given back stay that way
+        data.push(if sector == 0 { None } else { Some(self.read_sector(sector)?) });
+      }
+      fragments.push(data);
+    }
+    //values in the heap are rewritten after the table data
+    let mut heap_values = vec![];
+    for table_idx in 0..self.shape.tables.len() {
+      let table = &self.shape.tables[table_idx];
+      let name = table.name.clone();
+      let heap_columns: Vec<usize> = (0..table.columns.len()).filter(|&idx| table.columns[idx].typ.is_heap()).collect();
+      let rows: Vec<u64> = (0..table.row_count).filter(|&row| !table.is_deleted(row)).collect();
+      for row in rows {
+        for &column in &heap_columns {
+          let heap_ref = HeapRef::from_bytes(&self.table_read_row_column(&name, row, column)?);
+          heap_values.push((name.clone(), row, column, self.heap_read(heap_ref)?));
+        }
+      }
+    }
+
+    //everything except the header gets rewritten
+    self.pending.retain(|&sector, _| sector == 0);
+    self.shape.free.clear();
+    self.shape.heap_pages.clear();
+    let mut next_sector = 1;
+    for (table, data) in self.shape.tables.iter_mut().zip(&fragments) {
+      table.fragmentation = data.iter().map(|data| match data {
+        Some(_) => {
+          next_sector += 1;
+          next_sector - 1
+        },
+        None => 0,
+      }).collect();
+    }
+    self.header.sector_count = next_sector;
+    //the shape gets a new location right after the data in `write_shape`
+    self.header.shape_location = (0, 0);
+    self.header_dirty = true;
+    self.shape_dirty = true;
+
+    for (sector, data) in (1..).zip(fragments.iter().flatten().flatten()) {
+      self.write_sector(sector, data, 0)?;
+    }
+    for (name, row, column, data) in heap_values {
+      let heap_ref = self.heap_write(&data)?;
+      self.table_write_row_column(&name, row, column, &heap_ref.to_bytes())?;
+    }
+
     Ok(())
   }
 
+  /// Read `len` bytes starting at `offset` in the first sector, continuing into the next ones if needed
+  fn read_span(&mut self, sectors: &[u64], mut offset: usize, len: usize) -> Result<Vec<u8>> {
+    let mut buffer = Vec::with_capacity(len);
+    for &sector in sectors {
+      if buffer.len() == len {
+        break
+      }
+      let data = self.read_sector(sector)?;
+      let end = data.len().min(offset + len - buffer.len());
+      buffer.extend_from_slice(&data[offset..end]);
+      offset = 0;
+    }
+    ensure!(buffer.len() == len, "Data does not fit inside the sectors");
+    Ok(buffer)
+  }
+
+  /// Counterpart of `read_span`
+  fn write_span(&mut self, sectors: &[u64], mut offset: usize, data: &[u8]) -> Result<()> {
+    let sector_size = self.sector_size();
+    let mut written = 0;
+    for &sector in sectors {
+      if written == data.len() {
+        break
+      }
+      let chunk = (sector_size - offset).min(data.len() - written);
+      self.write_sector(sector, &data[written..(written + chunk)], offset)?;
+      written += chunk;
+      offset = 0;
+    }
+    ensure!(written == data.len(), "Data does not fit inside the sectors");
+    Ok(())
+  }
+
+  /// Sectors (starting from `sector_idx` in the fragmentation) covering `len` bytes at `offset`
+  fn table_span(&self, name: &str, sector_idx: usize, offset: usize, len: usize) -> Vec<u64> {
+    let sector_count = (offset + len).div_ceil(self.sector_size());
+    self.shape.get_table(name).unwrap().fragmentation[sector_idx..(sector_idx + sector_count)].to_vec()
+  }
+
   //TODO: proper error handling (error enum)
   //TODO: ensure that table exists
   //TODO: accept sth like Row instead of raw bytes
 
-  /// Warning: db data is reflected right away, but shape is not
-  /// Remember to call `sync_to_disk` to write that info to the disk
-  pub fn table_insert(&mut self, name: &str, data: &[u8]) -> Result<()> {
+  /// Warning: neither the row data nor the shape are written to the disk right away
+  /// Remember to call `sync_database` to commit them\
+  /// Deleted row slots are reused before new ones are appended, returns the id of the row
+  pub fn table_insert(&mut self, name: &str, data: &[u8]) -> Result<u64> {
+    let sector_size = self.sector_size();
     let table = self.shape.get_table_mut(name).unwrap();
 
     let row_size = table.byte_size();
 
-    let entries_per_fragment = SECTOR_SIZE / row_size;
-    let falls_into_fragment = table.row_count as usize / entries_per_fragment;
-
     //ensure data size
     ensure!(row_size == data.len());
 
-    //get offset and sector
-    let offset = row_size * (table.row_count as usize - falls_into_fragment * entries_per_fragment);
-    let mut sector = table.fragmentation.get(falls_into_fragment).copied();
+    //reuse a deleted slot, or increment row count
+    let row = match table.deleted.allocate() {
+      Some(row) => row,
+      None => {
+        table.row_count += 1;
+        table.row_count - 1
+      }
+    };
 
-    //increment row count
-    table.row_count += 1;
+    //get offset and sector
+    let (sector_idx, offset) = table.row_location(row, sector_size);
+    let sectors_per_fragment = table.sectors_per_fragment(sector_size);
+    let fragment_start = sector_idx - sector_idx % sectors_per_fragment;
 
-    //fragment table if needed
-    if table.fragmentation.len() <= falls_into_fragment {
-      let psector = self.allocate_sector();
+    //fragment table if needed (the fragment is either new, or it was given back when it became empty)
+    if table.fragmentation.get(fragment_start).is_none_or(|&sector| sector == 0) {
+      let sectors = self.allocate_consecutive_sectors(sectors_per_fragment as u64);
       //HACK: re-grab table to avoid borrowing issues
-      self.shape.get_table_mut(name).unwrap().fragmentation.push(psector);
-      sector = Some(psector);
+      let table = self.shape.get_table_mut(name).unwrap();
+      if table.fragmentation.len() <= fragment_start {
+        table.fragmentation.extend(sectors);
+      } else {
+        table.fragmentation.splice(fragment_start..(fragment_start + sectors_per_fragment), sectors);
+      }
     }
 
     //write data
-    self.write_sector(sector.unwrap(), data, offset)?;
+    let sectors = self.table_span(name, sector_idx, offset, row_size);
+    self.write_span(&sectors, offset, data)?;
 
     //mark shape as dirty
     self.shape_dirty = true;
 
+    Ok(row)
+  }
+
+  /// The slot is marked as deleted and reused by later inserts, the ids of other rows don't change\
+  /// Fragments without any rows left are given back
+  pub fn table_delete_row(&mut self, name: &str, row: u64) -> Result<()> {
+    let sector_size = self.sector_size();
+    let table = self.shape.get_table(name).unwrap();
+    ensure!(row < table.row_count && !table.is_deleted(row), "Row not found");
+
+    self.row_heap_free(name, row)?;
+
+    let table = self.shape.get_table_mut(name).unwrap();
+    table.deleted.free(row);
+    //deleted slots at the end don't need to be kept around
+    if let Some(start) = table.deleted.take_tail(table.row_count) {
+      table.row_count = start;
+    }
+
+    let sectors_per_fragment = table.sectors_per_fragment(sector_size);
+    let rows_per_fragment = table.rows_per_fragment(sector_size) as u64;
+    let needed_sectors = table.row_count.div_ceil(rows_per_fragment) as usize * sectors_per_fragment;
+    let mut freed: Vec<u64> = table.fragmentation.drain(needed_sectors.min(table.fragmentation.len())..).collect();
+    let fragment = (row / rows_per_fragment) as usize;
+    let fragment_sectors = (fragment * sectors_per_fragment)..((fragment + 1) * sectors_per_fragment);
+    if fragment_sectors.end <= table.fragmentation.len() && table.deleted.contains_range(table.fragment_rows(fragment, sector_size)) {
+      freed.extend(table.fragmentation[fragment_sectors].iter_mut().map(std::mem::take));
+    }
+    for sector in freed.into_iter().filter(|&sector| sector != 0) {
+      self.reclaim_sector(sector)?;
+    }
+
+    self.shape_dirty = true;
     Ok(())
   }
 
-  pub fn table_read_row_column(&mut self, name: &str, row: u64, column: usize) -> Result<Box<[u8]>> {
+  /// Sectors containing `len` bytes at `offset` inside of the row, and the offset in the first one
+  fn table_row_span(&self, name: &str, row: u64, offset: usize, len: usize) -> Result<(Vec<u64>, usize)> {
+    let sector_size = self.sector_size();
     let table = self.shape.get_table(name).unwrap();
     ensure!(row < table.row_count, "Row out of bounds");
+    ensure!(!table.is_deleted(row), "Row was deleted");
+    let (row_sector_idx, row_offset) = table.row_location(row, sector_size);
+    let sector_idx = row_sector_idx + (row_offset + offset) / sector_size;
+    let offset = (row_offset + offset) % sector_size;
+    Ok((self.table_span(name, sector_idx, offset, len), offset))
+  }
+
+  /// Sectors containing the column of a row, offset of it in the first one and its size
+  fn table_column_span(&self, name: &str, row: u64, column: usize) -> Result<(Vec<u64>, usize, usize)> {
+    let table = self.shape.get_table(name).unwrap();
     ensure!(column < table.columns.len(), "Column out of bounds");
-    let row_size = table.byte_size();
-    let entries_per_fragment = SECTOR_SIZE / row_size;
-    let falls_into_fragment = row / entries_per_fragment as u64;
-    let sector = table.fragmentation[falls_into_fragment as usize];
-    let mut buffer = vec![0; table.columns[column].typ.into_type_tree().byte_size()].into_boxed_slice();
-    let row_offset = row_size * (row as usize - falls_into_fragment as usize * entries_per_fragment);
-    let col_offset: usize = table.columns[..column]
-      .iter()
-      .map(|col| col.typ.into_type_tree().byte_size())
-      .sum();
-    self.data.seek(SeekFrom::Start(sector * SECTOR_SIZE as u64 + col_offset as u64 + row_offset as u64))?;
-    self.data.read_exact(&mut buffer[..])?;
-    Ok(buffer)
+    let column_size = table.columns[column].typ.into_type_tree().byte_size();
+    let (sectors, offset) = self.table_row_span(name, row, table.column_offset(column), column_size)?;
+    Ok((sectors, offset, column_size))
+  }
+
+  pub fn table_is_null(&mut self, name: &str, row: u64, column: usize) -> Result<bool> {
+    let table = self.shape.get_table(name).unwrap();
+    let Some(bit) = table.null_bit(column) else {
+      return Ok(false)
+    };
+    let (sectors, offset) = self.table_row_span(name, row, bit / 8, 1)?;
+    Ok(self.read_span(&sectors, offset, 1)?[0] & (1 << (bit % 8)) != 0)
+  }
+
+  /// Warning: like `table_insert`, this is only written to the disk by `sync_database`
+  pub fn table_set_null(&mut self, name: &str, row: u64, column: usize, null: bool) -> Result<()> {
+    let table = self.shape.get_table(name).unwrap();
+    let bit = table.null_bit(column).context("column is not nullable")?;
+    let (sectors, offset) = self.table_row_span(name, row, bit / 8, 1)?;
+    let mut byte = self.read_span(&sectors, offset, 1)?[0];
+    if null {
+      byte |= 1 << (bit % 8);
+    } else {
+      byte &= !(1 << (bit % 8));
+    }
+    self.write_span(&sectors, offset, &[byte])
+  }
+
+  pub fn table_read_row_column(&mut self, name: &str, row: u64, column: usize) -> Result<Box<[u8]>> {
+    let (sectors, offset, column_size) = self.table_column_span(name, row, column)?;
+    Ok(self.read_span(&sectors, offset, column_size)?.into())
+  }
+
+  /// Warning: like `table_insert`, this is only written to the disk by `sync_database`
+  pub fn table_write_row_column(&mut self, name: &str, row: u64, column: usize, data: &[u8]) -> Result<()> {
+    let (sectors, offset, column_size) = self.table_column_span(name, row, column)?;
+    ensure!(data.len() == column_size, "invalid length");
+    self.write_span(&sectors, offset, data)
   }
 }
 
@@ -300,15 +793,311 @@ impl Database<File> {
     self.data.sync_all()?;
     Ok(())
   }
+}
 
-  /// Truncate the database file size based on the current sector count\
-  /// This is not called automatically!\
-  pub fn truncate(&mut self) -> Result<()> {
-    let sector_len = self.header.sector_count * SECTOR_SIZE as u64;
-    let data_len = self.data.metadata()?.len();
-    if data_len > sector_len {
-      self.data.set_len(sector_len)?;
+#[cfg(test)]
+mod tests {
+  use std::{
+    io::{self, Read, Write, Seek, SeekFrom, Cursor},
+    rc::Rc,
+    cell::Cell,
+  };
+  use serde_json::json;
+  use crate::{
+    journal,
+    checksum::{self, CorruptionError},
+    testing::{MemoryDb, memory_db, request},
+  };
+  use super::{Database, RwData, DEFAULT_SECTOR_SIZE, HEADER_SLOT_SIZE};
+
+  /// Space for data in the sectors of a database with the default sector size
+  const SECTOR_DATA_SIZE: usize = DEFAULT_SECTOR_SIZE - checksum::TRAILER_SIZE;
+
+  /// Storage that fails every write while `failing` is set, like a full disk
+  struct Flaky {
+    data: Cursor<Vec<u8>>,
+    failing: Rc<Cell<bool>>,
+  }
+
+  impl Flaky {
+    fn check(&self) -> io::Result<()> {
+      match self.failing.get() {
+        true => Err(io::Error::other("disk full")),
+        false => Ok(()),
+      }
     }
-    Ok(())
+  }
+
+  impl Read for Flaky {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+      self.data.read(buf)
+    }
+  }
+
+  impl Write for Flaky {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      self.check()?;
+      self.data.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+      self.check()
+    }
+  }
+
+  impl Seek for Flaky {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+      self.data.seek(pos)
+    }
+  }
+
+  impl RwData for Flaky {
+    fn set_size(&mut self, len: u64) -> io::Result<()> {
+      self.check()?;
+      self.data.set_size(len)
+    }
+  }
+
+  /// Database with a single written sector, the batch writing it is committed
+  fn written_db() -> (MemoryDb, u64) {
+    let mut db = memory_db();
+    let sector = db.allocate_sector();
+    db.write_sector(sector, &[1; SECTOR_DATA_SIZE], 0).unwrap();
+    db.sync_database().unwrap();
+    (db, sector)
+  }
+
+  /// Everything `sync_database` does, except for applying the batch (like a crash right after the journal was written)
+  fn sync_into_journal(db: &mut MemoryDb) {
+    if db.shape_dirty {
+      db.write_shape().unwrap();
+    }
+    if db.header_dirty {
+      db.write_header().unwrap();
+    }
+    let record = db.pending_record();
+    journal::write_journal(db.journal.as_mut().unwrap(), &record).unwrap();
+  }
+
+  #[test]
+  fn committed_batches_are_replayed_from_the_journal() {
+    let (mut db, sector) = written_db();
+    db.write_sector(sector, &[2; 16], 0).unwrap();
+    sync_into_journal(&mut db);
+    let Database { data, journal, .. } = db;
+    //the database file itself doesn't have the batch yet
+    assert_eq!(data.get_ref()[sector as usize * DEFAULT_SECTOR_SIZE], 1);
+
+    let mut db = Database::with_journal(data, journal.unwrap()).unwrap();
+    db.read_database().unwrap();
+    assert_eq!(db.read_sector(sector).unwrap()[..17], [[2; 16].as_slice(), &[1]].concat());
+    assert!(db.journal.unwrap().get_ref().is_empty());
+  }
+
+  #[test]
+  fn uncommitted_batches_are_not_replayed() {
+    let (mut db, sector) = written_db();
+    db.write_sector(sector, &[2; 16], 0).unwrap();
+    sync_into_journal(&mut db);
+    let Database { data, journal, .. } = db;
+    //a torn journal, the commit marker never made it to the disk
+    let mut journal = journal.unwrap();
+    journal.get_mut().pop();
+
+    let mut db = Database::with_journal(data, journal).unwrap();
+    db.read_database().unwrap();
+    assert_eq!(*db.read_sector(sector).unwrap(), [1; SECTOR_DATA_SIZE]);
+  }
+
+  #[test]
+  fn failed_commits_keep_pending_sectors() {
+    let failing = Rc::new(Cell::new(false));
+    let flaky = || Flaky { data: Cursor::new(vec![]), failing: failing.clone() };
+    let mut db = Database::with_journal(flaky(), flaky()).unwrap();
+    db.sync_database().unwrap();
+    let sector = db.allocate_sector();
+    db.write_sector(sector, &[1; SECTOR_DATA_SIZE], 0).unwrap();
+    db.sync_database().unwrap();
+
+    db.write_sector(sector, &[2; SECTOR_DATA_SIZE], 0).unwrap();
+    failing.set(true);
+    assert!(db.sync_database().is_err());
+    assert_eq!(*db.read_sector(sector).unwrap(), [2; SECTOR_DATA_SIZE]);
+    //retrying once the disk works again commits the whole batch
+    failing.set(false);
+    db.sync_database().unwrap();
+    let mut db = Database::new(db.data.data).unwrap();
+    db.read_database().unwrap();
+    assert_eq!(*db.read_sector(sector).unwrap(), [2; SECTOR_DATA_SIZE]);
+  }
+
+  #[test]
+  fn sector_size_is_kept_in_the_database() {
+    let mut db = Database::with_journal(Cursor::new(vec![]), Cursor::new(vec![])).unwrap();
+    db.set_sector_size(4096).unwrap();
+    db.sync_database().unwrap();
+    let columns = json!([{"name": "x", "type": {"Text": 2000}}]);
+    request(&mut db, json!([
+      {"type": "TableCreate", "name": "a", "columns": columns},
+      {"type": "TableInsert", "name": "a", "columns": ["large"]},
+    ])).unwrap();
+    assert!(db.set_sector_size(1024).is_err());
+
+    let (data, journal) = db.into_files();
+    let mut db = Database::with_journal(data, journal.unwrap()).unwrap();
+    db.read_database().unwrap();
+    assert_eq!(db.sector_size(), 4096 - checksum::TRAILER_SIZE);
+    let result = request(&mut db, json!([{"type": "TableQuery", "name": "a", "columns": ["x"], "_rowid": 0}])).unwrap();
+    assert_eq!(result, json!([{"TableQuery": [["large"]]}]));
+    assert_eq!(db.into_files().0.get_ref().len() % 4096, 0);
+  }
+
+  #[test]
+  fn corrupted_sectors_are_detected() {
+    let mut db = memory_db();
+    request(&mut db, json!([
+      {"type": "TableCreate", "name": "a", "columns": [{"name": "x", "type": {"Text": 8}}]},
+      {"type": "TableInsert", "name": "a", "columns": ["first"]},
+    ])).unwrap();
+    let sector = db.shape.tables[0].fragmentation[0];
+    let (mut data, journal) = db.into_files();
+    data.get_mut()[sector as usize * DEFAULT_SECTOR_SIZE + 5] ^= 1;
+
+    let mut db = Database::with_journal(data, journal.unwrap()).unwrap();
+    db.read_database().unwrap();
+    let err = request(&mut db, json!([{"type": "TableQuery", "name": "a", "columns": ["x"], "_rowid": 0}])).unwrap_err();
+    assert!(err.chain().any(|err| err.is::<CorruptionError>()), "{err:#}");
+  }
+
+  #[test]
+  fn corrupted_shapes_are_detected() {
+    let mut db = memory_db();
+    request(&mut db, json!([{"type": "TableCreate", "name": "a", "columns": [{"name": "x", "type": {"Text": 8}}]}])).unwrap();
+    let shape_start = db.header.shape_location.0;
+    let (mut data, journal) = db.into_files();
+    data.get_mut()[shape_start as usize * DEFAULT_SECTOR_SIZE] ^= 1;
+
+    let mut db = Database::with_journal(data, journal.unwrap()).unwrap();
+    let err = db.read_database().unwrap_err();
+    assert!(err.chain().any(|err| err.is::<CorruptionError>()), "{err:#}");
+  }
+
+  #[test]
+  fn allocated_sectors_read_as_zeroes_until_written() {
+    let mut db = memory_db();
+    let sector = db.allocate_sector();
+    db.sync_database().unwrap();
+    assert_eq!(*db.read_sector(sector).unwrap(), [0; SECTOR_DATA_SIZE]);
+  }
+
+  #[test]
+  fn newest_valid_header_slot_is_used() {
+    let mut db = memory_db();
+    request(&mut db, json!([{"type": "TableCreate", "name": "a", "columns": [{"name": "x", "type": {"Text": 8}}]}])).unwrap();
+    request(&mut db, json!([{"type": "TableInsert", "name": "a", "columns": ["first"]}])).unwrap();
+    let sequence = db.header.sequence;
+    let (mut data, _) = db.into_files();
+
+    let mut db = Database::new(data.clone()).unwrap();
+    db.read_header().unwrap();
+    assert_eq!(db.header.sequence, sequence);
+    //a torn write of the newest slot leaves the other one
+    let slot = (sequence % 2) as usize * HEADER_SLOT_SIZE;
+    data.get_mut()[slot + 20] ^= 1;
+    let mut db = Database::new(data.clone()).unwrap();
+    db.read_header().unwrap();
+    assert_eq!(db.header.sequence, sequence - 1);
+    let other = (1 - sequence % 2) as usize * HEADER_SLOT_SIZE;
+    data.get_mut()[other + 20] ^= 1;
+    assert!(Database::new(data).unwrap().read_header().is_err());
+  }
+
+  #[test]
+  fn shape_is_never_written_over_its_old_location() {
+    let mut db = memory_db();
+    for table in 0..20 {
+      let old = db.header.shape_location;
+      request(&mut db, json!([{"type": "TableCreate", "name": format!("table {table}"), "columns": [{"name": "x", "type": {"Text": 8}}]}])).unwrap();
+      let new = db.header.shape_location;
+      assert!(new.1 <= old.0 || new.0 >= old.1, "{old:?} overlaps {new:?}");
+    }
+  }
+
+  #[test]
+  fn optimize_compacts_the_database() {
+    let mut db = memory_db();
+    request(&mut db, json!([
+      {"type": "TableCreate", "name": "a", "columns": [{"name": "x", "type": {"Text": 600}}]},
+      {"type": "TableCreate", "name": "b", "columns": [{"name": "x", "type": {"Text": 600}}]},
+      {"type": "TableInsert", "name": "a", "columns": ["first"]},
+      {"type": "TableInsert", "name": "b", "columns": ["second"]},
+      {"type": "TableInsert", "name": "a", "columns": ["third"]},
+      {"type": "TableInsert", "name": "b", "columns": ["fourth"]},
+    ])).unwrap();
+    request(&mut db, json!([{"type": "TableDelete", "name": "a"}])).unwrap();
+    assert!(db.shape.free.extents().next().is_some());
+
+    request(&mut db, json!([{"type": "Optimize"}])).unwrap();
+    assert!(db.shape.free.extents().next().is_none());
+    assert_eq!(db.shape.tables[0].fragmentation, [1, 2]);
+    let result = request(&mut db, json!([{"type": "TableQuery", "name": "b", "columns": ["x"], "_rowid": 1}])).unwrap();
+    assert_eq!(result, json!([{"TableQuery": [["fourth"]]}]));
+    assert!(db.check(false).unwrap().issues.is_empty());
+    let sector_count = db.header.sector_count;
+    assert_eq!(db.into_files().0.get_ref().len() as u64, sector_count * DEFAULT_SECTOR_SIZE as u64);
+  }
+  #[test]
+  fn given_back_fragments_dont_free_the_header() {
+    let mut db = memory_db();
+    request(&mut db, json!([
+      {"type": "TableCreate", "name": "a", "columns": [{"name": "x", "type": {"Text": 600}}]},
+      {"type": "TableInsert", "name": "a", "columns": ["0"]},
+      {"type": "TableInsert", "name": "a", "columns": ["1"]},
+    ])).unwrap();
+    //the first fragment is given back, its sector becomes 0 in `fragmentation`
+    request(&mut db, json!([{"type": "RowDelete", "name": "a", "_rowid": 0}])).unwrap();
+    assert_eq!(db.shape.get_table("a").unwrap().fragmentation[0], 0);
+    request(&mut db, json!([{"type": "TableDelete", "name": "a"}])).unwrap();
+    assert!(!db.shape.free.contains(0));
+    request(&mut db, json!([
+      {"type": "TableCreate", "name": "b", "columns": [{"name": "x", "type": {"Text": 600}}]},
+      {"type": "TableInsert", "name": "b", "columns": ["new"]},
+    ])).unwrap();
+
+    let (data, journal) = db.into_files();
+    let mut db = Database::with_journal(data, journal.unwrap()).unwrap();
+    db.read_database().unwrap();
+    let result = request(&mut db, json!([{"type": "TableQuery", "name": "b", "columns": ["x"], "_rowid": 0}])).unwrap();
+    assert_eq!(result, json!([{"TableQuery": [["new"]]}]));
+    assert!(db.check(false).unwrap().issues.is_empty());
+  }
+
+  #[test]
+  fn header_sector_cant_be_reclaimed() {
+    let mut db = memory_db();
+    assert!(db.reclaim_sector(0).is_err());
+    assert!(!db.shape.free.contains(0));
+  }
+
+  #[test]
+  fn leftover_temporary_sectors_are_cut_off() {
+    let mut db = memory_db();
+    request(&mut db, json!([
+      {"type": "TableCreate", "name": "a", "columns": [{"name": "x", "type": "Unsigned32"}]},
+      {"type": "TableInsert", "name": "a", "columns": [1]},
+    ])).unwrap();
+    let (start, len) = db.temporary_sectors().unwrap();
+    assert!(db.write_temporary_sector(start - 1, &[1; SECTOR_DATA_SIZE]).is_err());
+    db.write_temporary_sector(start + 1, &[1; SECTOR_DATA_SIZE]).unwrap();
+    assert_eq!(&*db.read_temporary_sector(start + 1).unwrap(), &[1; SECTOR_DATA_SIZE]);
+    //like a crash in the middle of a sort
+    let (data, journal) = db.into_files();
+    assert!(data.get_ref().len() as u64 > len);
+
+    let mut db = Database::with_journal(data, journal.unwrap()).unwrap();
+    db.read_database().unwrap();
+    assert_eq!(db.temporary_sectors().unwrap(), (start, len));
+    let result = request(&mut db, json!([{"type": "TableQuery", "name": "a", "columns": ["x"], "_rowid": 0}])).unwrap();
+    assert_eq!(result, json!([{"TableQuery": [[1]]}]));
   }
 }
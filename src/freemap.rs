@@ -0,0 +1,184 @@
+//! free-space map\
+//! free sectors (or row slots) are stored as sorted extents, adjacent ones are always merged
+
+use std::{collections::BTreeMap, ops::Range};
+use serde::{Serialize, Deserialize};
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct FreeMap {
+  /// `start -> end` (exclusive), extents never overlap or touch
+  extents: BTreeMap<u64, u64>,
+}
+
+impl FreeMap {
+  pub fn contains(&self, sector: u64) -> bool {
+    self.extents
+      .range(..=sector)
+      .next_back()
+      .is_some_and(|(_, &end)| sector < end)
+  }
+
+  pub fn contains_range(&self, range: Range<u64>) -> bool {
+    range.is_empty() || self.extents
+      .range(..=range.start)
+      .next_back()
+      .is_some_and(|(_, &end)| range.end <= end)
+  }
+
+  pub fn extents(&self) -> impl Iterator<Item = Range<u64>> + '_ {
+    self.extents.iter().map(|(&start, &end)| start..end)
+  }
+
+  pub fn sectors(&self) -> impl Iterator<Item = u64> + '_ {
+    self.extents().flatten()
+  }
+
+  pub fn clear(&mut self) {
+    self.extents.clear();
+  }
+
+  /// Mark the sector as free, merging it with neighbouring extents\
+  /// Returns `false` if it was free already
+  pub fn free(&mut self, sector: u64) -> bool {
+    self.free_range(sector..(sector + 1))
+  }
+
+  /// Returns `false` if any of the sectors were free already (they still get merged)
+  pub fn free_range(&mut self, range: Range<u64>) -> bool {
+    if range.is_empty() {
+      return true
+    }
+    let mut start = range.start;
+    let mut end = range.end;
+    let mut was_free = false;
+    //merge with the extent before (if it touches or overlaps)
+    if let Some((&prev_start, &prev_end)) = self.extents.range(..=start).next_back() {
+      if prev_end >= start {
+        was_free |= prev_end > start;
+        start = prev_start;
+        end = end.max(prev_end);
+        self.extents.remove(&prev_start);
+      }
+    }
+    //merge with all extents after it
+    while let Some((&next_start, &next_end)) = self.extents.range(start..).next() {
+      if next_start > end {
+        break
+      }
+      was_free |= next_start < end;
+      end = end.max(next_end);
+      self.extents.remove(&next_start);
+    }
+    self.extents.insert(start, end);
+    !was_free
+  }
+
+  /// Mark the sector as used\
+  /// Returns `false` if it wasn't free
+  pub fn take(&mut self, sector: u64) -> bool {
+    let Some((&start, &end)) = self.extents.range(..=sector).next_back() else {
+      return false
+    };
+    if sector >= end {
+      return false
+    }
+    self.extents.remove(&start);
+    if start < sector {
+      self.extents.insert(start, sector);
+    }
+    if sector + 1 < end {
+      self.extents.insert(sector + 1, end);
+    }
+    true
+  }
+
+  /// Take the lowest free sector
+  pub fn allocate(&mut self) -> Option<u64> {
+    let (&start, _) = self.extents.iter().next()?;
+    self.take(start);
+    Some(start)
+  }
+
+  /// Take the first run of `len` consecutive free sectors
+  pub fn allocate_run(&mut self, len: u64) -> Option<Range<u64>> {
+    let (&start, &end) = self.extents.iter().find(|(&start, &end)| end - start >= len)?;
+    self.extents.remove(&start);
+    if start + len < end {
+      self.extents.insert(start + len, end);
+    }
+    Some(start..(start + len))
+  }
+
+  /// Forget everything from `end` onwards
+  pub fn truncate(&mut self, end: u64) {
+    self.extents.retain(|&start, _| start < end);
+    if let Some((_, extent_end)) = self.extents.iter_mut().next_back() {
+      *extent_end = (*extent_end).min(end);
+    }
+  }
+
+  /// If the last extent ends at `end`, remove it and return its start\
+  /// Used to give free sectors at the end of the file back
+  pub fn take_tail(&mut self, end: u64) -> Option<u64> {
+    let (&start, &extent_end) = self.extents.iter().next_back()?;
+    if extent_end != end {
+      return None
+    }
+    self.extents.remove(&start);
+    Some(start)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::FreeMap;
+
+  /// `(start, end)` of every extent
+  fn extents(map: &FreeMap) -> Vec<(u64, u64)> {
+    map.extents().map(|extent| (extent.start, extent.end)).collect()
+  }
+
+  #[test]
+  fn neighbouring_extents_are_merged() {
+    let mut map = FreeMap::default();
+    assert!(map.free(5));
+    assert!(map.free(3));
+    assert_eq!(extents(&map), [(3, 4), (5, 6)]);
+    assert!(map.free(4));
+    assert_eq!(extents(&map), [(3, 6)]);
+    assert!(!map.free(4));
+    //overlapping ranges are merged too, but reported
+    assert!(!map.free_range(1..4));
+    assert!(map.free_range(6..10));
+    assert_eq!(extents(&map), [(1, 10)]);
+    assert!(map.contains(9));
+    assert!(!map.contains(10));
+  }
+
+  #[test]
+  fn taking_sectors_splits_extents() {
+    let mut map = FreeMap::default();
+    map.free_range(1..6);
+    assert!(map.take(3));
+    assert!(!map.take(3));
+    assert_eq!(extents(&map), [(1, 3), (4, 6)]);
+    assert_eq!(map.allocate(), Some(1));
+    assert_eq!(map.allocate_run(2), Some(4..6));
+    assert_eq!(map.allocate_run(2), None);
+    assert_eq!(extents(&map), [(2, 3)]);
+  }
+
+  #[test]
+  fn tail_and_truncate() {
+    let mut map = FreeMap::default();
+    map.free_range(2..4);
+    map.free_range(6..10);
+    assert_eq!(map.take_tail(9), None);
+    assert_eq!(map.take_tail(10), Some(6));
+    assert_eq!(extents(&map), [(2, 4)]);
+    map.truncate(3);
+    assert_eq!(extents(&map), [(2, 3)]);
+    map.truncate(2);
+    assert!(extents(&map).is_empty());
+  }
+}
@@ -1,18 +1,164 @@
 use serde::{Serialize, Deserialize};
+use anyhow::{Result, ensure};
+use crate::{database::DEFAULT_SECTOR_SIZE, checksum::{self, CorruptionError}};
+
+/// Every database file starts with this
+pub const MAGIC: [u8; 8] = *b"AWFULDB\0";
+
+/// Bumped on every change to the on-disk format
+pub const FORMAT_VERSION: u32 = 9;
+
+/// The first sector contains two header slots, the one with the higher `sequence` is the current one
+pub const HEADER_SLOT_SIZE: usize = 512;
+
+/// Both header slots have to fit in the first sector
+pub const MIN_SECTOR_SIZE: usize = 2 * HEADER_SLOT_SIZE;
+pub const MAX_SECTOR_SIZE: usize = 128 * 1024 * 1024;
+const _: () = assert!(DEFAULT_SECTOR_SIZE >= MIN_SECTOR_SIZE && DEFAULT_SECTOR_SIZE <= MAX_SECTOR_SIZE);
+
+pub fn validate_sector_size(sector_size: usize) -> Result<()> {
+  ensure!(
+    (MIN_SECTOR_SIZE..=MAX_SECTOR_SIZE).contains(&sector_size) && sector_size.is_power_of_two(),
+    "invalid sector size {}, it must be a power of two between {} and {}",
+    sector_size, MIN_SECTOR_SIZE, MAX_SECTOR_SIZE
+  );
+  Ok(())
+}
+
+/// Bitset of optional features used by the database file
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Features(pub u64);
+
+impl Features {
+  /// Some tables have variable-length columns stored in the heap
+  pub const HEAP: Features = Features(1 << 0);
+
+  /// Features this build knows how to handle
+  pub const SUPPORTED: Features = Features(Self::HEAP.0);
+
+  pub const fn contains(self, other: Features) -> bool {
+    self.0 & other.0 == other.0
+  }
+
+  pub fn insert(&mut self, other: Features) {
+    self.0 |= other.0;
+  }
+
+  pub const fn unsupported(self) -> Features {
+    Features(self.0 & !Self::SUPPORTED.0)
+  }
+}
 
 #[derive(Serialize, Deserialize, Clone, Copy, Debug)]
 pub struct DbHeader {
+  /// must be the first field, so it's at the very start of the file
+  pub magic: [u8; 8],
+  pub version: u32,
+  pub sector_size: u32,
+  pub features: Features,
   /// range of consecutive sectors containing the shape
   /// we're not using range directly as it's not `Copy`able
   pub shape_location: (u64, u64),
   pub sector_count: u64,
+  /// incremented on every write, decides which header slot is the current one
+  pub sequence: u64,
+  /// checksum of the whole shape area (including the padding)
+  pub shape_checksum: u32,
+  /// checksum of the header itself, computed with this field set to zero
+  pub checksum: u32,
+}
+
+impl DbHeader {
+  /// Check if the header belongs to a database this build can open
+  pub fn validate(&self) -> Result<()> {
+    ensure!(self.magic == MAGIC, "not an awfuldb database");
+    ensure!(
+      self.version <= FORMAT_VERSION,
+      "database format version {} is newer than the supported one ({}), please upgrade awfuldb",
+      self.version, FORMAT_VERSION
+    );
+    ensure!(
+      self.version == FORMAT_VERSION,
+      "database format version {} is too old (expected {}), run `awfuldb migrate` to upgrade it",
+      self.version, FORMAT_VERSION
+    );
+    validate_sector_size(self.sector_size as usize)?;
+    ensure!(
+      self.features.unsupported() == Features(0),
+      "database uses unsupported features ({:#x})",
+      self.features.unsupported().0
+    );
+    Ok(())
+  }
+
+  /// Decode and check a single header slot
+  pub fn read_slot(slot: &[u8]) -> Result<Self> {
+    let header: DbHeader = bincode::deserialize(slot)?;
+    header.validate()?;
+    header.verify_checksum()?;
+    Ok(header)
+  }
+
+  pub fn compute_checksum(&self) -> u32 {
+    let header = DbHeader { checksum: 0, ..*self };
+    checksum::checksum(&bincode::serialize(&header).unwrap())
+  }
+
+  pub fn verify_checksum(&self) -> Result<(), CorruptionError> {
+    if self.compute_checksum() == self.checksum {
+      Ok(())
+    } else {
+      Err(CorruptionError { what: "the header".into() })
+    }
+  }
 }
 
 impl Default for DbHeader {
   fn default() -> Self {
     Self {
+      magic: MAGIC,
+      version: FORMAT_VERSION,
+      sector_size: DEFAULT_SECTOR_SIZE as u32,
+      features: Features::default(),
       shape_location: (0, 0),
       sector_count: 1,
+      sequence: 0,
+      shape_checksum: 0,
+      checksum: 0,
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn only_the_current_version_and_known_features_are_accepted() {
+    assert!(DbHeader::default().validate().is_ok());
+    let old = DbHeader { version: FORMAT_VERSION - 1, ..DbHeader::default() };
+    assert!(old.validate().unwrap_err().to_string().contains("too old"));
+    let new = DbHeader { version: FORMAT_VERSION + 1, ..DbHeader::default() };
+    assert!(new.validate().unwrap_err().to_string().contains("please upgrade"));
+    let unknown = DbHeader { features: Features(1 << 40), ..DbHeader::default() };
+    assert!(unknown.validate().is_err());
+  }
+
+  #[test]
+  fn slots_with_a_wrong_checksum_are_rejected() {
+    let mut header = DbHeader { sequence: 3, ..DbHeader::default() };
+    header.checksum = header.compute_checksum();
+    let mut slot = bincode::serialize(&header).unwrap();
+    assert_eq!(DbHeader::read_slot(&slot).unwrap().sequence, 3);
+    *slot.last_mut().unwrap() ^= 1;
+    assert!(DbHeader::read_slot(&slot).is_err());
+  }
+  #[test]
+  fn sector_sizes_are_powers_of_two_that_fit_both_slots() {
+    assert!(validate_sector_size(1024).is_ok());
+    assert!(validate_sector_size(4096).is_ok());
+    assert!(validate_sector_size(512).is_err());
+    assert!(validate_sector_size(3000).is_err());
+    assert!(validate_sector_size(MAX_SECTOR_SIZE * 2).is_err());
+  }
+}
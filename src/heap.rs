@@ -0,0 +1,419 @@
+//! storage for variable-length values\
+//! small values are packed into heap pages shared by many values (of any table),
+//! larger ones get a chain of whole sectors, each one starting with the (little-endian `u64`) number of the next sector
+
+use anyhow::{Result, Context, ensure};
+use crate::database::{Database, RwData};
+
+/// Size of the next sector pointer at the start of every chained sector
+const NEXT_SIZE: usize = 8;
+
+/// Size of the slot count at the start of every heap page
+const SLOT_COUNT_SIZE: usize = 4;
+
+/// Size of an entry in the slot directory of a heap page: offset and length of the value (both `u32`)
+const SLOT_SIZE: usize = 8;
+
+/// What's actually stored in the row for `VarText`/`VarBlob` columns: `[sector u64][len u32][slot u32]`\
+/// `slot` is 0 for values in a chain starting at `sector`, otherwise the value is in slot `slot - 1` of the heap page `sector`\
+/// Empty values don't use any sectors, `sector` is 0 for them
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HeapRef {
+  pub sector: u64,
+  pub len: u64,
+  pub slot: u32,
+}
+
+impl HeapRef {
+  pub const SIZE: usize = 16;
+
+  pub fn to_bytes(self) -> [u8; Self::SIZE] {
+    let mut bytes = [0; Self::SIZE];
+    bytes[..8].copy_from_slice(&self.sector.to_le_bytes());
+    bytes[8..12].copy_from_slice(&(self.len as u32).to_le_bytes());
+    bytes[12..].copy_from_slice(&self.slot.to_le_bytes());
+    bytes
+  }
+
+  pub fn from_bytes(bytes: &[u8]) -> Self {
+    Self {
+      sector: u64::from_le_bytes(bytes[..8].try_into().unwrap()),
+      len: u32::from_le_bytes(bytes[8..12].try_into().unwrap()).into(),
+      slot: u32::from_le_bytes(bytes[12..Self::SIZE].try_into().unwrap()),
+    }
+  }
+
+  /// Whether the value is stored in a heap page (and not in a chain)
+  pub fn is_packed(self) -> bool {
+    self.slot != 0
+  }
+}
+
+/// Sector shared by small values: `[slot count u32][slot directory]`, the values are packed at the end of the sector\
+/// Free slots have offset 0, they're reused before new ones are added
+pub struct HeapPage {
+  /// offset and length of the value in every slot
+  slots: Vec<(u32, u32)>,
+  data: Box<[u8]>,
+}
+
+impl HeapPage {
+  fn empty(sector_size: usize) -> Self {
+    Self { slots: vec![], data: vec![0; sector_size].into() }
+  }
+
+  /// Fails if the slot directory doesn't fit in the sector, or a slot points outside of it
+  pub fn parse(data: Box<[u8]>) -> Result<Self> {
+    let count = u32::from_le_bytes(data[..SLOT_COUNT_SIZE].try_into().unwrap()) as usize;
+    let directory_end = SLOT_COUNT_SIZE + count * SLOT_SIZE;
+    ensure!(directory_end <= data.len(), "heap page has {count} slots, which don't fit in the sector");
+    let slots: Vec<(u32, u32)> = data[SLOT_COUNT_SIZE..directory_end]
+      .chunks(SLOT_SIZE)
+      .map(|slot| (
+        u32::from_le_bytes(slot[..4].try_into().unwrap()),
+        u32::from_le_bytes(slot[4..].try_into().unwrap()),
+      ))
+      .collect();
+    for (slot, &(offset, len)) in slots.iter().enumerate() {
+      ensure!(
+        offset == 0 || (offset as usize >= directory_end && offset as usize + len as usize <= data.len()),
+        "slot {slot} of the heap page points outside of it"
+      );
+    }
+    Ok(Self { slots, data })
+  }
+
+  pub fn value(&self, slot: u32) -> Option<&[u8]> {
+    let &(offset, len) = self.slots.get(slot as usize).filter(|(offset, _)| *offset != 0)?;
+    Some(&self.data[(offset as usize)..(offset as usize + len as usize)])
+  }
+
+  /// Slots that contain a value
+  pub fn used_slots(&self) -> impl Iterator<Item = u32> + '_ {
+    (0..self.slots.len() as u32).filter(|&slot| self.slots[slot as usize].0 != 0)
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.slots.is_empty()
+  }
+
+  /// Bytes left for new values, including the slots they need
+  pub fn free_bytes(&self) -> u32 {
+    let used: usize = self.slots.iter().map(|&(_, len)| len as usize).sum();
+    (self.data.len() - SLOT_COUNT_SIZE - self.slots.len() * SLOT_SIZE - used) as u32
+  }
+
+  /// Where the values start, everything between the slot directory and this is free
+  fn values_start(&self) -> usize {
+    self.slots.iter().filter(|(offset, _)| *offset != 0).map(|&(offset, _)| offset as usize).min().unwrap_or(self.data.len())
+  }
+
+  /// Move the values to the end of the sector, so all free space is in one piece
+  fn compact(&mut self) {
+    let mut order: Vec<usize> = (0..self.slots.len()).filter(|&slot| self.slots[slot].0 != 0).collect();
+    order.sort_unstable_by_key(|&slot| std::cmp::Reverse(self.slots[slot].0));
+    let mut end = self.data.len();
+    for slot in order {
+      let (offset, len) = self.slots[slot];
+      let start = end - len as usize;
+      self.data.copy_within((offset as usize)..(offset as usize + len as usize), start);
+      self.slots[slot].0 = start as u32;
+      end = start;
+    }
+  }
+
+  /// Store the value in a free slot (or a new one), the caller makes sure that there's enough space (see `free_bytes`)
+  fn insert(&mut self, value: &[u8]) -> u32 {
+    let slot = match self.slots.iter().position(|(offset, _)| *offset == 0) {
+      Some(slot) => slot,
+      None => {
+        self.slots.push((0, 0));
+        self.slots.len() - 1
+      },
+    };
+    let directory_end = SLOT_COUNT_SIZE + self.slots.len() * SLOT_SIZE;
+    if self.values_start() - directory_end < value.len() {
+      self.compact();
+    }
+    let offset = self.values_start() - value.len();
+    self.data[offset..(offset + value.len())].copy_from_slice(value);
+    self.slots[slot] = (offset as u32, value.len() as u32);
+    slot as u32
+  }
+
+  /// Free the slot, free slots at the end of the directory are dropped
+  pub fn remove(&mut self, slot: u32) {
+    self.slots[slot as usize] = (0, 0);
+    while self.slots.last().is_some_and(|(offset, _)| *offset == 0) {
+      self.slots.pop();
+    }
+  }
+
+  pub fn into_bytes(mut self) -> Box<[u8]> {
+    self.data[..SLOT_COUNT_SIZE].copy_from_slice(&(self.slots.len() as u32).to_le_bytes());
+    for (idx, (offset, len)) in self.slots.iter().enumerate() {
+      let start = SLOT_COUNT_SIZE + idx * SLOT_SIZE;
+      self.data[start..(start + 4)].copy_from_slice(&offset.to_le_bytes());
+      self.data[(start + 4)..(start + SLOT_SIZE)].copy_from_slice(&len.to_le_bytes());
+    }
+    self.data
+  }
+}
+
+impl<T: RwData> Database<T> {
+  fn heap_payload_size(&self) -> usize {
+    self.sector_size() - NEXT_SIZE
+  }
+
+  /// Values up to a quarter of a sector are packed into heap pages
+  fn heap_packed_limit(&self) -> usize {
+    self.sector_size() / 4
+  }
+
+  /// Store the value in a heap page with enough space left (or a new one), or in newly allocated heap sectors if it's large
+  pub fn heap_write(&mut self, data: &[u8]) -> Result<HeapRef> {
+    if data.is_empty() {
+      return Ok(HeapRef::default())
+    }
+    ensure!(u32::try_from(data.len()).is_ok(), "value is too large ({} bytes)", data.len());
+    if data.len() <= self.heap_packed_limit() {
+      return self.heap_write_packed(data)
+    }
+    let mut sectors = vec![0; data.len().div_ceil(self.heap_payload_size())];
+    self.allocate_multiple_sectors(&mut sectors);
+    for (idx, chunk) in data.chunks(self.heap_payload_size()).enumerate() {
+      let next = sectors.get(idx + 1).copied().unwrap_or(0);
+      let mut buffer = vec![0; self.sector_size()];
+      buffer[..NEXT_SIZE].copy_from_slice(&next.to_le_bytes());
+      buffer[NEXT_SIZE..(NEXT_SIZE + chunk.len())].copy_from_slice(chunk);
+      self.write_sector(sectors[idx], &buffer, 0)?;
+    }
+    Ok(HeapRef { sector: sectors[0], len: data.len() as u64, slot: 0 })
+  }
+
+  fn heap_write_packed(&mut self, data: &[u8]) -> Result<HeapRef> {
+    //a new slot might be needed
+    let needed = (data.len() + SLOT_SIZE) as u32;
+    let page = self.shape.heap_pages.iter().find(|&(_, &free)| free >= needed).map(|(&sector, _)| sector);
+    let (sector, mut page) = match page {
+      Some(sector) => (sector, self.heap_page(sector)?),
+      None => (self.allocate_sector(), HeapPage::empty(self.sector_size())),
+    };
+    let slot = page.insert(data);
+    self.shape.heap_pages.insert(sector, page.free_bytes());
+    self.mark_shape_dirty();
+    self.write_sector(sector, &page.into_bytes(), 0)?;
+    Ok(HeapRef { sector, len: data.len() as u64, slot: slot + 1 })
+  }
+
+  pub fn heap_page(&mut self, sector: u64) -> Result<HeapPage> {
+    ensure!(self.shape.heap_pages.contains_key(&sector), "sector {sector} is not a heap page");
+    HeapPage::parse(self.read_sector(sector)?).with_context(|| format!("heap page {sector} is broken"))
+  }
+
+  /// Value of a packed heap reference\
+  /// Fails if the slot is free, or its value has a different length
+  fn heap_read_packed(&mut self, heap_ref: HeapRef) -> Result<Vec<u8>> {
+    let page = self.heap_page(heap_ref.sector)?;
+    let value = page.value(heap_ref.slot - 1).with_context(|| format!(
+      "slot {} of heap page {} is free", heap_ref.slot - 1, heap_ref.sector
+    ))?;
+    ensure!(
+      value.len() as u64 == heap_ref.len,
+      "slot {} of heap page {} has {} bytes instead of {}", heap_ref.slot - 1, heap_ref.sector, value.len(), heap_ref.len
+    );
+    Ok(value.to_vec())
+  }
+
+  /// Sectors used by a value stored in a chain, in order\
+  /// Fails if the chain is broken (too short, or pointing outside of the file)
+  pub fn heap_sectors(&mut self, heap_ref: HeapRef) -> Result<Vec<u64>> {
+    let count = (heap_ref.len as usize).div_ceil(self.heap_payload_size());
+    let mut sectors = Vec::with_capacity(count);
+    let mut sector = heap_ref.sector;
+    for _ in 0..count {
+      ensure!(
+        sector != 0 && sector < self.header.sector_count,
+        "heap chain points at an invalid sector ({sector})"
+      );
+      sectors.push(sector);
+      let data = self.read_sector(sector)?;
+      sector = u64::from_le_bytes(data[..NEXT_SIZE].try_into().unwrap());
+    }
+    Ok(sectors)
+  }
+
+  pub fn heap_read(&mut self, heap_ref: HeapRef) -> Result<Vec<u8>> {
+    if heap_ref.is_packed() {
+      return self.heap_read_packed(heap_ref)
+    }
+    let mut data = Vec::with_capacity(heap_ref.len as usize);
+    for sector in self.heap_sectors(heap_ref)? {
+      let chunk_len = (heap_ref.len as usize - data.len()).min(self.heap_payload_size());
+      data.extend_from_slice(&self.read_sector(sector)?[NEXT_SIZE..(NEXT_SIZE + chunk_len)]);
+    }
+    Ok(data)
+  }
+
+  /// Give the space used by the value back, heap pages are freed once their last value is
+  pub fn heap_free(&mut self, heap_ref: HeapRef) -> Result<()> {
+    if !heap_ref.is_packed() {
+      for sector in self.heap_sectors(heap_ref)? {
+        self.reclaim_sector(sector)?;
+      }
+      return Ok(())
+    }
+    let mut page = self.heap_page(heap_ref.sector)?;
+    ensure!(
+      page.value(heap_ref.slot - 1).is_some(),
+      "slot {} of heap page {} is free already", heap_ref.slot - 1, heap_ref.sector
+    );
+    page.remove(heap_ref.slot - 1);
+    self.mark_shape_dirty();
+    if page.is_empty() {
+      self.shape.heap_pages.remove(&heap_ref.sector);
+      self.reclaim_sector(heap_ref.sector)
+    } else {
+      self.shape.heap_pages.insert(heap_ref.sector, page.free_bytes());
+      self.write_sector(heap_ref.sector, &page.into_bytes(), 0)
+    }
+  }
+
+  /// Free heap values of the row
+  pub fn row_heap_free(&mut self, name: &str, row: u64) -> Result<()> {
+    let table = self.shape.get_table(name).context("table not found")?;
+    let heap_columns: Vec<usize> = table.columns
+      .iter()
+      .enumerate()
+      .filter(|(_, column)| column.typ.is_heap())
+      .map(|(idx, _)| idx)
+      .collect();
+    for column in heap_columns {
+      let heap_ref = HeapRef::from_bytes(&self.table_read_row_column(name, row, column)?);
+      self.heap_free(heap_ref)?;
+    }
+    Ok(())
+  }
+
+  /// Free heap values of every row in the table
+  pub fn table_heap_free(&mut self, name: &str) -> Result<()> {
+    let table = self.shape.get_table(name).context("table not found")?;
+    let rows: Vec<u64> = (0..table.row_count).filter(|&row| !table.is_deleted(row)).collect();
+    for row in rows {
+      self.row_heap_free(name, row)?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+  use crate::testing::{MemoryDb, memory_db, request, rows};
+  use super::*;
+
+  fn notes(values: &[String]) -> MemoryDb {
+    let mut db = memory_db();
+    let mut ops = vec![json!({"type": "TableCreate", "name": "t", "columns": [{"name": "note", "type": "VarText"}]})];
+    ops.extend(values.iter().map(|value| json!({"type": "TableInsert", "name": "t", "columns": [value]})));
+    request(&mut db, json!(ops)).unwrap();
+    db
+  }
+
+  fn heap_ref(db: &mut MemoryDb, row: u64) -> HeapRef {
+    HeapRef::from_bytes(&db.table_read_row_column("t", row, 0).unwrap())
+  }
+
+  #[test]
+  fn small_values_share_heap_pages() {
+    let values: Vec<String> = (0..20).map(|i| format!("note {i}")).collect();
+    let mut db = notes(&values);
+    let first = heap_ref(&mut db, 0);
+    assert!(first.is_packed());
+    for row in 1..20 {
+      assert_eq!(heap_ref(&mut db, row).sector, first.sector);
+    }
+    assert_eq!(db.shape.heap_pages.len(), 1);
+    for (row, value) in values.iter().enumerate() {
+      let result = request(&mut db, json!([{"type": "TableQuery", "name": "t", "columns": ["note"], "_rowid": row}])).unwrap();
+      assert_eq!(rows(&result[0]), [json!([value])]);
+    }
+    assert!(db.check(false).unwrap().issues.is_empty());
+  }
+
+  #[test]
+  fn large_values_get_whole_sectors() {
+    let mut db = notes(&["x".repeat(3000)]);
+    let heap_ref = heap_ref(&mut db, 0);
+    assert!(!heap_ref.is_packed());
+    assert_eq!(db.heap_sectors(heap_ref).unwrap().len(), 3);
+    assert!(db.shape.heap_pages.is_empty());
+  }
+
+  #[test]
+  fn heap_pages_are_freed_with_their_last_value() {
+    let values: Vec<String> = (0..10).map(|i| format!("{i}").repeat(200)).collect();
+    let mut db = notes(&values);
+    assert!(db.shape.heap_pages.len() > 1);
+    request(&mut db, json!([
+      {"type": "TableCreate", "name": "u", "columns": [{"name": "note", "type": "VarText"}]},
+      {"type": "TableInsert", "name": "u", "columns": ["kept"]},
+    ])).unwrap();
+    let kept = HeapRef::from_bytes(&db.table_read_row_column("u", 0, 0).unwrap());
+    request(&mut db, json!([{"type": "TableDelete", "name": "t"}])).unwrap();
+    assert_eq!(db.shape.heap_pages.keys().copied().collect::<Vec<u64>>(), [kept.sector]);
+    assert!(db.check(false).unwrap().issues.is_empty());
+    let result = request(&mut db, json!([{"type": "TableQuery", "name": "u", "columns": ["note"], "_rowid": 0}])).unwrap();
+    assert_eq!(result, json!([{"TableQuery": [["kept"]]}]));
+  }
+
+  #[test]
+  fn freed_slots_are_reused() {
+    let values: Vec<String> = (0..10).map(|i| format!("{i}").repeat(200)).collect();
+    let mut db = notes(&values);
+    assert!(db.shape.heap_pages.len() > 1);
+    let ops: Vec<_> = (0..9).map(|row| json!({"type": "RowDelete", "name": "t", "_rowid": row})).collect();
+    request(&mut db, json!(ops)).unwrap();
+    let HeapRef { sector: last, slot: last_slot, .. } = heap_ref(&mut db, 9);
+    assert_eq!(db.shape.heap_pages.keys().copied().collect::<Vec<u64>>(), [last]);
+    assert!(db.check(false).unwrap().issues.is_empty());
+    //slots freed by the deletes are reused (and so is the slot of row 0)
+    request(&mut db, json!([{"type": "TableInsert", "name": "t", "columns": ["short"]}])).unwrap();
+    let heap_ref = heap_ref(&mut db, 0);
+    assert_eq!(heap_ref.sector, last);
+    assert!(heap_ref.slot < last_slot);
+    assert_eq!(db.shape.heap_pages.len(), 1);
+  }
+
+  #[test]
+  fn check_repairs_heap_pages() {
+    let mut db = notes(&["a".to_string(), "b".to_string()]);
+    let page = heap_ref(&mut db, 0).sector;
+    //the row doesn't point at its value anymore, so the slot leaks
+    db.table_write_row_column("t", 0, 0, &HeapRef::default().to_bytes()).unwrap();
+    *db.shape.heap_pages.get_mut(&page).unwrap() += 1;
+    assert_eq!(db.check(true).unwrap().issues.len(), 2);
+    assert!(db.check(false).unwrap().issues.is_empty());
+    assert_eq!(db.heap_page(page).unwrap().used_slots().collect::<Vec<u32>>(), [1]);
+  }
+
+  #[test]
+  fn heap_pages_are_compacted_when_needed() {
+    let mut page = HeapPage::empty(256);
+    let values: Vec<Vec<u8>> = (0..4).map(|i| vec![i; 50]).collect();
+    for value in &values {
+      page.insert(value);
+    }
+    page.remove(1);
+    page.remove(2);
+    assert_eq!(page.free_bytes(), 256 - 4 - 4 * 8 - 100);
+    //there are 100 free bytes in the middle, but not enough between the directory and the values
+    let large = vec![9; 110];
+    assert_eq!(page.insert(&large), 1);
+    let page = HeapPage::parse(page.into_bytes()).unwrap();
+    assert_eq!(page.value(0), Some(&values[0][..]));
+    assert_eq!(page.value(1), Some(&large[..]));
+    assert_eq!(page.value(2), None);
+    assert_eq!(page.value(3), Some(&values[3][..]));
+  }
+}
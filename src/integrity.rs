@@ -0,0 +1,279 @@
+//! referential integrity of pointer columns
+
+use anyhow::{Result, Context, bail, ensure};
+use rustc_hash::FxHashMap;
+use crate::{
+  database::{Database, RwData},
+  operations::DbRowColumnValue,
+  shape::{DbShape, Table, OnDelete, DROPPED_TABLE},
+  types::Type,
+  freemap::FreeMap,
+};
+
+/// Rows that are going to be deleted, by table index
+type Doomed = FxHashMap<usize, FreeMap>;
+
+/// Fails if a (non-NULL) value of a pointer column doesn't point at an existing row
+pub fn ensure_target(shape: &DbShape, table: &Table, column: usize, value: &DbRowColumnValue) -> Result<()> {
+  let (Type::Pointer(target), DbRowColumnValue::Integer(row)) = (table.columns[column].typ, value) else {
+    return Ok(())
+  };
+  let target_table = shape.tables.get(target as usize).with_context(|| format!(
+    "column `{}` points at a table that was deleted, so it can only be NULL",
+    table.column_names()[column]
+  ))?;
+  let exists = u64::try_from(*row).is_ok_and(|row| row < target_table.row_count && !target_table.is_deleted(row));
+  if !exists {
+    bail!(
+      "column `{}` points at row {row} of `{}`, which doesn't exist",
+      table.column_names()[column], target_table.name
+    );
+  }
+  Ok(())
+}
+
+/// A pointer column: index of its table, the column, index of the table it points at and `on_delete`
+struct PointerColumn {
+  table: usize,
+  column: usize,
+  target: usize,
+  on_delete: OnDelete,
+}
+
+impl<T: RwData> Database<T> {
+  /// Every pointer column of every table, including the ones pointing at their own table
+  fn pointer_columns(&self) -> Vec<PointerColumn> {
+    self.shape.tables
+      .iter()
+      .enumerate()
+      .flat_map(|(table_idx, table)| {
+        table.columns
+          .iter()
+          .enumerate()
+          .filter_map(move |(column, c)| match c.typ {
+            Type::Pointer(target) if target != DROPPED_TABLE => Some(PointerColumn {
+              table: table_idx,
+              column,
+              target: target as usize,
+              on_delete: c.on_delete,
+            }),
+            _ => None,
+          })
+      })
+      .collect()
+  }
+
+  /// Rows (that are not doomed themselves) pointing at a doomed row through the column, and the rows they point at
+  fn doomed_references(&mut self, pointer: &PointerColumn, doomed: &Doomed) -> Result<Vec<(u64, u64)>> {
+    let Some(targets) = doomed.get(&pointer.target) else {
+      return Ok(vec![])
+    };
+    let name = self.shape.tables[pointer.table].name.clone();
+    let mut references = vec![];
+    for row in self.table_scan(&name, None)? {
+      if doomed.get(&pointer.table).is_some_and(|rows| rows.contains(row)) {
+        continue
+      }
+      let DbRowColumnValue::Integer(target) = self.load_value(&name, row, pointer.column)? else {
+        continue
+      };
+      if let Some(target) = u64::try_from(target).ok().filter(|&target| targets.contains(target)) {
+        references.push((row, target));
+      }
+    }
+    Ok(references)
+  }
+
+  /// Apply `on_delete` of every pointer to the doomed rows (which are not deleted here)\
+  /// Cascades add the rows they reach to `doomed` until there's nothing new, every row is only added once,
+  /// so cycles of pointers (like a row pointing at itself) end too\
+  /// Only then RESTRICT is checked, and SetNull clears pointers, both for rows that are not going to be deleted
+  fn delete_references(&mut self, doomed: &mut Doomed) -> Result<()> {
+    let pointers = self.pointer_columns();
+    loop {
+      let mut reached = false;
+      for pointer in pointers.iter().filter(|pointer| pointer.on_delete == OnDelete::Cascade) {
+        for (row, _) in self.doomed_references(pointer, doomed)? {
+          doomed.entry(pointer.table).or_default().free(row);
+          reached = true;
+        }
+      }
+      if !reached {
+        break
+      }
+    }
+    for pointer in &pointers {
+      let references = self.doomed_references(pointer, doomed)?;
+      let name = self.shape.tables[pointer.table].name.clone();
+      match pointer.on_delete {
+        OnDelete::Restrict => if let Some((row, target)) = references.first() {
+          let target_name = &self.shape.tables[pointer.target].name;
+          bail!("row {target} of `{target_name}` is still pointed at by row {row} of `{name}`");
+        },
+        OnDelete::SetNull => for (row, _) in references {
+          self.table_update_row(&name, row, &[(pointer.column, DbRowColumnValue::Null)])?;
+        },
+        OnDelete::Cascade => debug_assert!(references.is_empty()),
+      }
+    }
+    Ok(())
+  }
+
+  /// Delete the rows, taking care of the rows pointing at them first
+  pub fn delete_rows(&mut self, name: &str, rows: &[u64]) -> Result<()> {
+    let table_idx = *self.shape.table_map.get(name).context("table not found")?;
+    let table = &self.shape.tables[table_idx];
+    let mut targets = FreeMap::default();
+    for &row in rows {
+      ensure!(row < table.row_count && !table.is_deleted(row), "Row not found");
+      targets.free(row);
+    }
+    let mut doomed = Doomed::from_iter([(table_idx, targets)]);
+    self.delete_references(&mut doomed)?;
+    for (table_idx, rows) in doomed {
+      let name = self.shape.tables[table_idx].name.clone();
+      for row in rows.sectors() {
+        self.table_delete_row(&name, row)?;
+      }
+    }
+    Ok(())
+  }
+
+  /// Take care of the rows pointing at the table before it's deleted\
+  /// Rows of the table itself are not deleted, as the whole table goes away
+  pub fn delete_table_references(&mut self, name: &str) -> Result<()> {
+    let table_idx = *self.shape.table_map.get(name).context("table not found")?;
+    let mut targets = FreeMap::default();
+    targets.free_range(0..self.shape.tables[table_idx].row_count);
+    let mut doomed = Doomed::from_iter([(table_idx, targets)]);
+    self.delete_references(&mut doomed)?;
+    for (idx, rows) in doomed.into_iter().filter(|&(idx, _)| idx != table_idx) {
+      let name = self.shape.tables[idx].name.clone();
+      for row in rows.sectors() {
+        self.table_delete_row(&name, row)?;
+      }
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::{json, Value};
+  use crate::{
+    testing::{MemoryDb, memory_db, request, rows},
+    types::Type,
+  };
+
+  fn column(db: &mut MemoryDb, table: &str, column: &str) -> Vec<Value> {
+    let result = request(db, json!([{"type": "TableQuery", "name": table, "columns": [column]}])).unwrap();
+    rows(&result[0])
+  }
+
+  fn linked_nodes(on_delete: &str) -> MemoryDb {
+    let mut db = memory_db();
+    request(&mut db, json!([
+      {"type": "TableCreate", "name": "node", "columns": [
+        {"name": "id", "type": "Unsigned8"},
+        {"name": "next", "type": {"Pointer": "node"}, "nullable": true, "on_delete": on_delete},
+      ]},
+      {"type": "TableInsert", "name": "node", "columns": [0, null]},
+      {"type": "TableInsert", "name": "node", "columns": [1, 0]},
+      {"type": "TableInsert", "name": "node", "columns": [2, 1]},
+      {"type": "TableInsert", "name": "node", "columns": [3, null]},
+      //0 -> 2 -> 1 -> 0 is a cycle, 3 is on its own
+      {"type": "TableUpdate", "name": "node", "set": {"next": 2}, "_rowid": 0},
+    ])).unwrap();
+    db
+  }
+
+  #[test]
+  fn cascade_through_a_cycle_of_self_references() {
+    let mut db = linked_nodes("Cascade");
+    request(&mut db, json!([{"type": "RowDelete", "name": "node", "_rowid": 1}])).unwrap();
+    assert_eq!(column(&mut db, "node", "id"), [json!([3])]);
+    assert!(db.check(false).unwrap().issues.is_empty());
+  }
+
+  #[test]
+  fn cascade_through_a_cycle_of_two_tables() {
+    let mut db = memory_db();
+    request(&mut db, json!([
+      {"type": "TableCreate", "name": "a", "columns": [{"name": "p", "type": {"Pointer": "a"}, "nullable": true, "on_delete": "Cascade"}]},
+      {"type": "TableCreate", "name": "b", "columns": [{"name": "q", "type": {"Pointer": "a"}, "on_delete": "Cascade"}]},
+    ])).unwrap();
+    //databases created before pointers had to point at existing tables can have cycles of tables
+    db.shape.tables[0].columns[0].typ = Type::Pointer(1);
+    request(&mut db, json!([
+      {"type": "TableInsert", "name": "a", "columns": [null]},
+      {"type": "TableInsert", "name": "b", "columns": [0]},
+      {"type": "TableUpdate", "name": "a", "set": {"p": 0}, "_rowid": 0},
+      {"type": "RowDelete", "name": "a", "_rowid": 0},
+    ])).unwrap();
+    assert!(column(&mut db, "a", "p").is_empty());
+    assert!(column(&mut db, "b", "q").is_empty());
+  }
+
+  #[test]
+  fn set_null_clears_self_references() {
+    let mut db = linked_nodes("SetNull");
+    request(&mut db, json!([{"type": "RowDelete", "name": "node", "_rowid": 1}])).unwrap();
+    assert_eq!(column(&mut db, "node", "next"), [json!([2]), json!([null]), json!([null])]);
+  }
+
+  #[test]
+  fn restrict_ignores_rows_that_are_deleted_too() {
+    let mut db = linked_nodes("Restrict");
+    let err = request(&mut db, json!([{"type": "RowDelete", "name": "node", "_rowid": 1}])).unwrap_err();
+    assert!(err.to_string().contains("still pointed at by row 2"));
+    //a row pointing at itself doesn't stop its own delete
+    request(&mut db, json!([
+      {"type": "TableUpdate", "name": "node", "set": {"next": 3}, "_rowid": 3},
+      {"type": "RowDelete", "name": "node", "_rowid": 3},
+    ])).unwrap();
+    assert_eq!(column(&mut db, "node", "id"), [json!([0]), json!([1]), json!([2])]);
+    //rows matching a predicate are deleted together, so the cycle can go at once
+    let result = request(&mut db, json!([{"type": "RowDelete", "name": "node", "where": {"Lt": ["id", 3]}}])).unwrap();
+    assert_eq!(result, json!([{"Affected": 3}]));
+    assert!(column(&mut db, "node", "id").is_empty());
+  }
+
+  #[test]
+  fn table_delete_applies_on_delete() {
+    let mut db = linked_nodes("Cascade");
+    request(&mut db, json!([
+      {"type": "TableCreate", "name": "tag", "columns": [{"name": "node", "type": {"Pointer": "node"}, "on_delete": "Cascade"}]},
+      {"type": "TableCreate", "name": "pin", "columns": [{"name": "node", "type": {"Pointer": "node"}}]},
+      {"type": "TableInsert", "name": "tag", "columns": [0]},
+      {"type": "TableInsert", "name": "tag", "columns": [3]},
+      {"type": "TableInsert", "name": "pin", "columns": [3]},
+    ])).unwrap();
+    let err = request(&mut db, json!([{"type": "TableDelete", "name": "node"}])).unwrap_err();
+    assert!(err.to_string().contains("still pointed at"));
+    request(&mut db, json!([
+      {"type": "TableDelete", "name": "pin"},
+      {"type": "TableDelete", "name": "node"},
+    ])).unwrap();
+    assert!(column(&mut db, "tag", "node").is_empty());
+    assert!(db.check(false).unwrap().issues.is_empty());
+  }
+
+  #[test]
+  fn pointers_must_point_at_existing_tables() {
+    let mut db = memory_db();
+    let err = request(&mut db, json!([
+      {"type": "TableCreate", "name": "a", "columns": [{"name": "p", "type": {"Pointer": 1}}]},
+    ])).unwrap_err();
+    assert!(err.to_string().contains("doesn't exist"));
+    request(&mut db, json!([
+      {"type": "TableCreate", "name": "a", "columns": [{"name": "p", "type": {"Pointer": 0}, "nullable": true}]},
+      {"type": "TableInsert", "name": "a", "columns": [null]},
+      {"type": "TableInsert", "name": "a", "columns": [0]},
+    ])).unwrap();
+    //and at existing rows
+    let err = request(&mut db, json!([{"type": "TableInsert", "name": "a", "columns": [5]}])).unwrap_err();
+    assert!(err.to_string().contains("doesn't exist"), "{err}");
+    let err = request(&mut db, json!([{"type": "TableUpdate", "name": "a", "set": {"p": 2}, "_rowid": 0}])).unwrap_err();
+    assert!(err.to_string().contains("doesn't exist"), "{err}");
+  }
+}
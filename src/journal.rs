@@ -0,0 +1,108 @@
+//! write-ahead log\
+//! every batch of dirty sectors is written here (and synced) before it touches the database file\
+//! if we crash while applying the batch, it gets replayed on the next `read_database`
+
+use std::io::SeekFrom;
+use serde::{Serialize, Deserialize};
+use anyhow::Result;
+use crate::database::RwData;
+
+const JOURNAL_MAGIC: [u8; 8] = *b"AWFLJRNL";
+const JOURNAL_COMMIT: [u8; 8] = *b"AWFLCMIT";
+
+#[derive(Serialize, Deserialize)]
+pub struct JournalRecord {
+  /// full sector images, `(sector, data)`
+  pub sectors: Vec<(u64, Vec<u8>)>,
+}
+
+/// Write the record and the commit marker, syncing after each step\
+/// The commit marker is only written after the record itself is on the disk,
+/// so a torn record can never be mistaken for a committed one
+pub fn write_journal<T: RwData>(journal: &mut T, record: &JournalRecord) -> Result<()> {
+  let payload = bincode::serialize(record)?;
+  journal.set_size(0)?;
+  journal.seek(SeekFrom::Start(0))?;
+  journal.write_all(&JOURNAL_MAGIC)?;
+  journal.write_all(&(payload.len() as u64).to_le_bytes())?;
+  journal.write_all(&payload)?;
+  journal.sync()?;
+  journal.write_all(&JOURNAL_COMMIT)?;
+  journal.sync()?;
+  Ok(())
+}
+
+/// Returns the record if the journal contains a fully committed one\
+/// Empty, torn or otherwise incomplete journals are ignored (the batch never reached the database file)
+pub fn read_journal<T: RwData>(journal: &mut T) -> Result<Option<JournalRecord>> {
+  let len = journal.seek(SeekFrom::End(0))?;
+  if len < (JOURNAL_MAGIC.len() + 8 + JOURNAL_COMMIT.len()) as u64 {
+    return Ok(None)
+  }
+  journal.seek(SeekFrom::Start(0))?;
+  let mut magic = [0; 8];
+  journal.read_exact(&mut magic)?;
+  if magic != JOURNAL_MAGIC {
+    return Ok(None)
+  }
+  let mut payload_len = [0; 8];
+  journal.read_exact(&mut payload_len)?;
+  let payload_len = u64::from_le_bytes(payload_len);
+  if len != (JOURNAL_MAGIC.len() + 8 + JOURNAL_COMMIT.len()) as u64 + payload_len {
+    return Ok(None)
+  }
+  let mut payload = vec![0; payload_len as usize];
+  journal.read_exact(&mut payload)?;
+  let mut commit = [0; 8];
+  journal.read_exact(&mut commit)?;
+  if commit != JOURNAL_COMMIT {
+    return Ok(None)
+  }
+  Ok(Some(bincode::deserialize(&payload)?))
+}
+
+/// Mark the journal as applied
+pub fn clear_journal<T: RwData>(journal: &mut T) -> Result<()> {
+  journal.set_size(0)?;
+  journal.sync()?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::Cursor;
+  use super::*;
+
+  fn written(record: &JournalRecord) -> Vec<u8> {
+    let mut journal = Cursor::new(vec![]);
+    write_journal(&mut journal, record).unwrap();
+    journal.into_inner()
+  }
+
+  #[test]
+  fn committed_records_are_read_back() {
+    let record = JournalRecord { sectors: vec![(0, vec![1; 16]), (5, vec![2; 16])] };
+    let read = read_journal(&mut Cursor::new(written(&record))).unwrap().unwrap();
+    assert_eq!(read.sectors, record.sectors);
+  }
+
+  #[test]
+  fn torn_records_are_ignored() {
+    let data = written(&JournalRecord { sectors: vec![(3, vec![7; 64])] });
+    //every prefix is what a crash in the middle of writing could leave behind
+    for len in 0..data.len() {
+      assert!(read_journal(&mut Cursor::new(data[..len].to_vec())).unwrap().is_none(), "{len} bytes");
+    }
+    let mut uncommitted = data.clone();
+    *uncommitted.last_mut().unwrap() ^= 1;
+    assert!(read_journal(&mut Cursor::new(uncommitted)).unwrap().is_none());
+  }
+
+  #[test]
+  fn cleared_journals_are_empty() {
+    let mut journal = Cursor::new(written(&JournalRecord { sectors: vec![(1, vec![0; 8])] }));
+    clear_journal(&mut journal).unwrap();
+    assert!(journal.get_ref().is_empty());
+    assert!(read_journal(&mut journal).unwrap().is_none());
+  }
+}
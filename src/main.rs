@@ -5,17 +5,30 @@ use std::{
   fs::File,
   sync::{Arc, Mutex},
   io::{Seek, SeekFrom, self},
-  path::PathBuf, net::IpAddr
+  path::{Path, PathBuf}, net::IpAddr,
+  time::Duration,
 };
 use rouille::{Request, Response};
 
 pub(crate) mod types;
 pub(crate) mod shape;
+pub(crate) mod freemap;
+pub(crate) mod heap;
 pub(crate) mod database;
 pub(crate) mod operations;
+pub(crate) mod query;
+pub(crate) mod aggregate;
+pub(crate) mod integrity;
 pub(crate) mod header;
+pub(crate) mod journal;
+pub(crate) mod transaction;
+pub(crate) mod migration;
+pub(crate) mod checksum;
+pub(crate) mod check;
+#[cfg(test)]
+pub(crate) mod testing;
 
-use database::Database;
+use database::{Database, DEFAULT_SECTOR_SIZE};
 
 #[derive(Parser)]
 #[command(author, version, arg_required_else_help = true)]
@@ -28,6 +41,9 @@ struct Cli {
 enum Commands {
   Create(CreateCommand),
   Run(RunCommand),
+  Migrate(MigrateCommand),
+  Check(CheckCommand),
+  Optimize(OptimizeCommand),
 }
 
 #[derive(Args)]
@@ -36,6 +52,8 @@ struct CreateCommand {
   path: PathBuf,
   #[clap(short = 'f')]
   force: bool,
+  #[clap(short = 's', long, default_value_t = DEFAULT_SECTOR_SIZE, help = "Size of a sector in bytes (a power of two, at least 1024), rows wider than this span several sectors")]
+  sector_size: usize,
 }
 
 #[derive(Args)]
@@ -48,16 +66,50 @@ struct RunCommand {
   addr: IpAddr,
   #[clap(short = 'p', default_value = "12012", help = "The port to bind to")]
   port: u16,
+  #[clap(short = 't', default_value = "60", help = "Roll back transactions idle for longer than this many seconds")]
+  transaction_timeout: u64,
 }
 
+#[derive(Args)]
+struct MigrateCommand {
+  #[clap(help = "The path to the database file")]
+  path: PathBuf,
+  #[clap(short = 'n', long, help = "Only check if the migration would succeed, without writing anything")]
+  dry_run: bool,
+}
+
+#[derive(Args)]
+struct CheckCommand {
+  #[clap(help = "The path to the database file")]
+  path: PathBuf,
+  #[clap(short = 'r', long, help = "Rebuild a consistent shape, fixing everything that can be fixed")]
+  repair: bool,
+}
+
+#[derive(Args)]
+struct OptimizeCommand {
+  #[clap(help = "The path to the database file")]
+  path: PathBuf,
+}
+
+/// Header carrying the id of the open transaction, both in requests and responses
+const TRANSACTION_HEADER: &str = "X-Transaction";
+
 fn handle_req(request: &Request, db: &mut Database<File>) -> Result<Response> {
+  let transaction = request.header(TRANSACTION_HEADER)
+    .map(|id| id.trim().parse().context("invalid transaction id"))
+    .transpose()?;
   let req = serde_json::from_reader(request.data().context("no request body")?)?;
-  let res = db.perform_multiple(req)?;
+  let (res, transaction) = db.perform_request(transaction, req)?;
   db.sync_database()?;
   // if let Err(err) = db.sync_fs() {
   //   eprint!("failed to sync db to fs: {}", err);
   // }
-  Ok(Response::json(&res))
+  let response = Response::json(&res);
+  Ok(match transaction {
+    Some(id) => response.with_additional_header(TRANSACTION_HEADER, id.to_string()),
+    None => response,
+  })
 }
 
 fn handle_error(request: Result<Response>) -> Response {
@@ -66,13 +118,30 @@ fn handle_error(request: Result<Response>) -> Response {
   })
 }
 
-fn txt_opening(path: &PathBuf) {
+/// The write-ahead log lives next to the database file, `<path>-journal`
+fn journal_path(path: &Path) -> PathBuf {
+  let mut journal_path = path.as_os_str().to_owned();
+  journal_path.push("-journal");
+  journal_path.into()
+}
+
+fn open_journal(path: &Path, truncate: bool) -> File {
+  File::options()
+    .read(true)
+    .write(true)
+    .create(true)
+    .truncate(truncate)
+    .open(journal_path(path))
+    .expect("failed to open the journal file")
+}
+
+fn txt_opening(path: &Path) {
   #[allow(clippy::print_literal)] {
   println!(
     "{}{}🗃️ {}",
     path
       .canonicalize()
-      .unwrap_or_else(|_| path.clone())
+      .unwrap_or_else(|_| path.to_path_buf())
       .as_os_str()
       .to_string_lossy()
       .dimmed(),
@@ -81,6 +150,27 @@ fn txt_opening(path: &PathBuf) {
   );}
 }
 
+fn txt_migration(report: &migration::MigrationReport, dry_run: bool) {
+  println!(
+    "🚚 {} {} {} {}",
+    "Migrating database from format version".bold(),
+    report.from.to_string().bold(),
+    "to".bold(),
+    report.to.to_string().bold(),
+  );
+  for step in &report.steps {
+    println!("   {} {}", "•".dimmed(), step);
+  }
+  if let Some(backup) = &report.backup {
+    println!("💾 {} {}", "Backup saved to".bold(), backup.to_string_lossy().dimmed());
+  }
+  if dry_run {
+    println!("🧪 {}", "Dry run, nothing was written".bold().green());
+  } else {
+    println!("🐤 {}", "Database migrated".bold().green());
+  }
+}
+
 fn main() {
   println!(
     "{}",
@@ -99,6 +189,10 @@ fn main() {
   match &cli.command {
     Some(Commands::Create(args)) => {
       txt_opening(&args.path);
+      if let Err(err) = header::validate_sector_size(args.sector_size) {
+        println!("❌ {}\n{}", "Invalid sector size".red().bold(), format!("{err:#}").dimmed());
+        return
+      }
       let data = match File::options().create(args.force).create_new(!args.force).write(true).open(&args.path) {
         Ok(x) => x,
         Err(err) => match err.kind() {
@@ -113,7 +207,8 @@ fn main() {
           _ => panic!("{:?}", err),
         }
       };
-      let mut db = Database::new(data).unwrap();
+      let mut db = Database::with_journal(data, open_journal(&args.path, true)).unwrap();
+      db.set_sector_size(args.sector_size).unwrap();
       db.sync_database().unwrap();
       db.truncate().unwrap();
       db.sync_fs().unwrap();
@@ -121,6 +216,16 @@ fn main() {
     },
     Some(Commands::Run(args)) => {
       txt_opening(&args.path);
+      if args.path.metadata().is_ok_and(|meta| meta.len() > 0) {
+        match migration::migrate_file(&args.path, &journal_path(&args.path), false) {
+          Ok(report) if report.from != report.to => txt_migration(&report, false),
+          Ok(_) => (),
+          Err(err) => {
+            println!("❌ {}\n{}", "Failed to migrate the database".red().bold(), format!("{err:#}").dimmed());
+            return
+          }
+        }
+      }
       let mut data = match File::options().read(true).write(true).create(args.create).open(&args.path) {
         Ok(x) => x,
         Err(err) => match err.kind() {
@@ -133,8 +238,10 @@ fn main() {
       };
       let size = data.seek(SeekFrom::End(0)).unwrap();
 
-      let db = Arc::new(Mutex::new(Database::new(data).unwrap()));
+      let journal = open_journal(&args.path, false);
+      let db = Arc::new(Mutex::new(Database::with_journal(data, journal).unwrap()));
       let mut dblock = db.lock().unwrap();
+      dblock.transactions.timeout = Duration::from_secs(args.transaction_timeout);
 
       if args.create && size == 0 {
         println!("🐤 {}", "Creating new database...".bold());
@@ -148,11 +255,21 @@ fn main() {
             "(but --create was specified)".dimmed()
           );
         }
-        dblock.read_database().unwrap();
+        if let Err(err) = dblock.read_database() {
+          println!("❌ {}\n{}", "Failed to open the database".red().bold(), format!("{err:#}").dimmed());
+          return
+        }
       }
 
       drop(dblock);
 
+      //idle transactions are rolled back even without new requests, so the sectors preserved for them don't pile up
+      let expiry_db = Arc::clone(&db);
+      std::thread::spawn(move || loop {
+        std::thread::sleep(Duration::from_secs(1));
+        expiry_db.lock().unwrap_or_else(|poison| poison.into_inner()).transactions.expire();
+      });
+
       println!(
         "📡 {} `{}:{}`",
         "Running on".green().bold(),
@@ -165,6 +282,102 @@ fn main() {
         })))
       });
     }
+    Some(Commands::Migrate(args)) => {
+      txt_opening(&args.path);
+      match migration::migrate_file(&args.path, &journal_path(&args.path), args.dry_run) {
+        Ok(report) if report.from == report.to => {
+          println!("👌 {}", "Database is already up to date".bold().green());
+        },
+        Ok(report) => txt_migration(&report, args.dry_run),
+        Err(err) => {
+          println!("❌ {}\n{}", "Failed to migrate the database".red().bold(), format!("{err:#}").dimmed());
+        }
+      }
+    },
+    Some(Commands::Check(args)) => {
+      txt_opening(&args.path);
+      let data = match File::options().read(true).write(args.repair).open(&args.path) {
+        Ok(x) => x,
+        Err(err) => match err.kind() {
+          io::ErrorKind::NotFound => {
+            println!("❌ {}", "File not found".red().bold());
+            return
+          }
+          _ => panic!("{:?}", err),
+        }
+      };
+      //without --repair nothing is written: the journal is neither created nor replayed, only looked at
+      let mut db = if args.repair {
+        Database::with_journal(data, open_journal(&args.path, false)).unwrap()
+      } else if let Ok(journal) = File::open(journal_path(&args.path)) {
+        Database::with_journal(data, journal).unwrap()
+      } else {
+        Database::new(data).unwrap()
+      };
+      let read = if args.repair { db.read_database() } else { db.read_database_without_replay() };
+      if let Err(err) = read {
+        println!("❌ {}\n{}", "Failed to open the database".red().bold(), format!("{err:#}").dimmed());
+        return
+      }
+      let report = match db.check(args.repair) {
+        Ok(report) => report,
+        Err(err) => {
+          println!("❌ {}\n{}", "Failed to check the database".red().bold(), format!("{err:#}").dimmed());
+          return
+        }
+      };
+      if report.issues.is_empty() {
+        println!("👌 {}", "No issues found".bold().green());
+        return
+      }
+      for issue in &report.issues {
+        let marker = match (issue.repairable, report.repaired) {
+          (true, true) => "🔧",
+          (true, false) => "⚠️ ",
+          (false, _) => "❌",
+        };
+        println!("{marker} {issue}");
+      }
+      let repairable = report.issues.iter().filter(|issue| issue.repairable).count();
+      let unrepairable = report.issues.len() - repairable;
+      if report.repaired && repairable > 0 {
+        db.sync_database().unwrap();
+        db.sync_fs().unwrap();
+        println!("🐤 {}", format!("Repaired {repairable} issues").bold().green());
+      } else if repairable > 0 {
+        println!("{}", format!("{repairable} issues can be fixed with --repair").dimmed());
+      }
+      if unrepairable > 0 {
+        println!("{}", format!("{unrepairable} issues can't be repaired").red().bold());
+      }
+    },
+    Some(Commands::Optimize(args)) => {
+      txt_opening(&args.path);
+      let data = match File::options().read(true).write(true).open(&args.path) {
+        Ok(x) => x,
+        Err(err) => match err.kind() {
+          io::ErrorKind::NotFound => {
+            println!("❌ {}", "File not found".red().bold());
+            return
+          }
+          _ => panic!("{:?}", err),
+        }
+      };
+      let mut db = Database::with_journal(data, open_journal(&args.path, false)).unwrap();
+      if let Err(err) = db.read_database() {
+        println!("❌ {}\n{}", "Failed to open the database".red().bold(), format!("{err:#}").dimmed());
+        return
+      }
+      let sectors_before = db.header.sector_count;
+      db.optimize().unwrap();
+      db.sync_database().unwrap();
+      db.sync_fs().unwrap();
+      println!(
+        "🐤 {} {}",
+        "Database optimized".bold().green(),
+        format!("({} -> {} sectors)", sectors_before, db.header.sector_count).dimmed()
+      );
+    },
     _ => ()
   }
 }
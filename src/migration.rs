@@ -0,0 +1,200 @@
+//! on-disk format migrations\
+//! every step upgrades a released format straight to the current one\
+//! steps work on an in-memory copy of the whole file: they read it with the types frozen for their version,
+//! and write the result through the current code, so they keep working after the current types change\
+//! formats that were never released (the versions between two releases) have no step
+
+use std::{
+  fs::{self, File},
+  io::Cursor,
+  path::{Path, PathBuf},
+};
+use anyhow::{Result, Context, ensure, bail};
+use crate::{
+  database::Database,
+  header::{FORMAT_VERSION, MAGIC, HEADER_SLOT_SIZE},
+  journal,
+};
+
+mod v0;
+
+pub struct Migration {
+  /// version the step upgrades from, it always upgrades to `FORMAT_VERSION`
+  pub from: u32,
+  pub description: &'static str,
+  pub upgrade: fn(&mut Vec<u8>) -> Result<()>,
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+  Migration {
+    from: 0,
+    description: "rebuild the original format (without a magic number) in the current one",
+    upgrade: v0::upgrade,
+  },
+];
+
+pub struct MigrationReport {
+  pub from: u32,
+  pub to: u32,
+  pub steps: Vec<&'static str>,
+  /// copy of the database made before migrating it, `None` for dry runs
+  pub backup: Option<PathBuf>,
+}
+
+/// Figure out the format version of a database image\
+/// Files from before the header had a magic number are version 0
+pub fn detect_version(image: &[u8]) -> Result<u32> {
+  //since v3, the header might be in either of the slots
+  for slot in image.chunks(HEADER_SLOT_SIZE).take(2) {
+    if slot.starts_with(&MAGIC) {
+      ensure!(slot.len() >= MAGIC.len() + 4, "truncated header");
+      let version = &slot[MAGIC.len()..(MAGIC.len() + 4)];
+      return Ok(u32::from_le_bytes(version.try_into().unwrap()))
+    }
+  }
+  if image.len() >= v0::SECTOR_SIZE {
+    if let Ok(header) = bincode::deserialize::<v0::DbHeader>(&image[..v0::SECTOR_SIZE]) {
+      if header.looks_valid(image.len()) {
+        return Ok(0)
+      }
+    }
+  }
+  bail!("not an awfuldb database")
+}
+
+/// Upgrade the image to the current format version
+pub fn migrate(image: &mut Vec<u8>) -> Result<MigrationReport> {
+  let from = detect_version(image)?;
+  ensure!(
+    from <= FORMAT_VERSION,
+    "database format version {} is newer than the supported one ({}), please upgrade awfuldb",
+    from, FORMAT_VERSION
+  );
+  if from == FORMAT_VERSION {
+    return Ok(MigrationReport { from, to: FORMAT_VERSION, steps: vec![], backup: None })
+  }
+  let migration = MIGRATIONS
+    .iter()
+    .find(|migration| migration.from == from)
+    .with_context(|| format!("format version {from} was never released, so it can't be migrated. please recreate the database"))?;
+  (migration.upgrade)(image).with_context(|| format!("migration from format version {from} failed"))?;
+  ensure!(detect_version(image)? == FORMAT_VERSION, "migration from format version {from} produced a wrong version");
+  //make sure the result can actually be opened
+  Database::new(Cursor::new(image.clone()))?
+    .read_database()
+    .context("migrated database is invalid")?;
+  Ok(MigrationReport { from, to: FORMAT_VERSION, steps: vec![migration.description], backup: None })
+}
+
+/// Migrate the database file in place (if it's not up to date already)\
+/// The original file is copied to `<path>.v<version>.bak` first, and the migrated one replaces it atomically\
+/// With `dry_run`, the migration is only performed in memory and nothing is written
+pub fn migrate_file(path: &Path, journal_path: &Path, dry_run: bool) -> Result<MigrationReport> {
+  let mut image = fs::read(path).context("failed to read the database file")?;
+
+  //the journal may still contain a batch that never made it to the database file
+  let mut journal_file = File::options().read(true).write(true).open(journal_path).ok();
+  if let Some(record) = journal_file.as_mut().map(journal::read_journal).transpose()?.flatten() {
+    for (sector, data) in &record.sectors {
+      let start = *sector as usize * data.len();
+      if image.len() < start + data.len() {
+        image.resize(start + data.len(), 0);
+      }
+      image[start..(start + data.len())].copy_from_slice(data);
+    }
+  }
+
+  let mut report = migrate(&mut image)?;
+  if dry_run || report.from == report.to {
+    return Ok(report)
+  }
+
+  let mut backup_path = path.as_os_str().to_owned();
+  backup_path.push(format!(".v{}.bak", report.from));
+  let backup_path = PathBuf::from(backup_path);
+  fs::copy(path, &backup_path).context("failed to back up the database")?;
+  report.backup = Some(backup_path);
+
+  let mut temp_path = path.as_os_str().to_owned();
+  temp_path.push(".migrating");
+  let temp_path = PathBuf::from(temp_path);
+  fs::write(&temp_path, &image)?;
+  File::open(&temp_path)?.sync_all()?;
+  fs::rename(&temp_path, path)?;
+
+  //the journalled batch is a part of the migrated file now
+  if let Some(journal_file) = &mut journal_file {
+    journal::clear_journal(journal_file)?;
+  }
+
+  Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{io::Cursor, collections::VecDeque};
+  use serde_json::json;
+  use crate::testing::{memory_db, request, rows};
+  use super::*;
+
+  /// Database in the original format: a reclaimed sector, a fragment with two rows of a table and the shape
+  fn v0_image() -> Vec<u8> {
+    let sector_size = v0::SECTOR_SIZE;
+    let mut image = vec![0; 4 * sector_size];
+    bincode::serialize_into(&mut image[..], &v0::DbHeader { shape_location: (3, 4), sector_count: 4 }).unwrap();
+    //rows are an `Unsigned32` followed by a `Text(8)` (its length, then the text)
+    for (row, (number, text)) in [(7u32, "seven"), (8, "eight")].into_iter().enumerate() {
+      let start = 2 * sector_size + row * 16;
+      image[start..(start + 4)].copy_from_slice(&number.to_le_bytes());
+      image[(start + 4)..(start + 8)].copy_from_slice(&(text.len() as u32).to_le_bytes());
+      image[(start + 8)..(start + 8 + text.len())].copy_from_slice(text.as_bytes());
+    }
+    let shape = v0::DbShape {
+      reclaim: VecDeque::from([1]),
+      table_map: [("t".to_string(), 0)].into_iter().collect(),
+      tables: vec![v0::Table {
+        name: "t".to_string(),
+        columns: vec![
+          v0::Column { typ: v0::Type::Unsigned32, nullable: false },
+          v0::Column { typ: v0::Type::Text(8), nullable: true },
+        ],
+        column_map: [("number".to_string(), 0), ("text".to_string(), 1)].into_iter().collect(),
+        fragmentation: vec![2],
+        row_count: 2,
+      }],
+    };
+    bincode::serialize_into(&mut image[(3 * sector_size)..], &shape).unwrap();
+    image
+  }
+
+  #[test]
+  fn migrates_the_original_format_to_the_current_one() {
+    let mut image = v0_image();
+    assert_eq!(detect_version(&image).unwrap(), 0);
+    let report = migrate(&mut image).unwrap();
+    assert_eq!((report.from, report.to, report.steps.len()), (0, FORMAT_VERSION, 1));
+    assert_eq!(detect_version(&image).unwrap(), FORMAT_VERSION);
+
+    let mut db = Database::new(Cursor::new(image)).unwrap();
+    db.read_database().unwrap();
+    let result = request(&mut db, json!([
+      {"type": "TableQuery", "name": "t", "columns": ["text"], "_rowid": 0},
+      {"type": "TableQuery", "name": "t", "columns": ["text"], "_rowid": 1},
+    ])).unwrap();
+    assert_eq!(rows(&result[0]), [json!(["seven"])]);
+    assert_eq!(rows(&result[1]), [json!(["eight"])]);
+    //migrating it again doesn't do anything
+    let mut image = db.into_files().0.into_inner();
+    assert!(migrate(&mut image).unwrap().steps.is_empty());
+  }
+
+  #[test]
+  fn unreleased_versions_cant_be_migrated() {
+    let mut image = memory_db().into_files().0.into_inner();
+    for slot in image.chunks_mut(HEADER_SLOT_SIZE).take(2).filter(|slot| slot.starts_with(&MAGIC)) {
+      slot[MAGIC.len()..(MAGIC.len() + 4)].copy_from_slice(&(FORMAT_VERSION - 1).to_le_bytes());
+    }
+    let Err(err) = migrate(&mut image) else { panic!("migrated an unreleased version") };
+    assert!(err.to_string().contains("never released"), "{err:#}");
+  }
+}
@@ -0,0 +1,158 @@
+//! the original format, before the header had a magic number\
+//! everything needed to read it is frozen here, the database is then rebuilt through the current code
+
+use std::{collections::VecDeque, io::Cursor};
+use serde::{Serialize, Deserialize};
+use rustc_hash::FxHashMap;
+use anyhow::{Result, Context, ensure};
+use crate::{
+  database::Database,
+  operations::{DbOperation, DbColumn, DbTypeExt},
+  types,
+};
+
+/// there was no way to change it back then
+pub const SECTOR_SIZE: usize = 1024;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct DbHeader {
+  pub shape_location: (u64, u64),
+  pub sector_count: u64,
+}
+
+impl DbHeader {
+  /// There's no magic number to check, so this only checks whether the header makes sense
+  pub fn looks_valid(&self, image_len: usize) -> bool {
+    let (shape_start, shape_end) = self.shape_location;
+    self.sector_count >= 1 &&
+    shape_start <= shape_end &&
+    shape_end <= self.sector_count &&
+    (shape_start >= 1 || shape_end == 0) &&
+    image_len >= SECTOR_SIZE
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub enum Type {
+  Pointer(u32),
+  Unsigned8,
+  Unsigned16,
+  Unsigned32,
+  Unsigned64,
+  Signed8,
+  Signed16,
+  Signed32,
+  Signed64,
+  Float32,
+  Float64,
+  Text(usize),
+  Blob(usize),
+}
+
+impl Type {
+  fn byte_size(self) -> usize {
+    match self {
+      Type::Unsigned8 | Type::Signed8 => 1,
+      Type::Unsigned16 | Type::Signed16 => 2,
+      Type::Unsigned32 | Type::Signed32 | Type::Float32 => 4,
+      Type::Pointer(_) | Type::Unsigned64 | Type::Signed64 | Type::Float64 => 8,
+      Type::Text(size) => size + 4,
+      Type::Blob(size) => size,
+    }
+  }
+
+  /// Same type in the current format
+  fn upgrade(self) -> types::Type {
+    match self {
+      Type::Pointer(table) => types::Type::Pointer(table),
+      Type::Unsigned8 => types::Type::Unsigned8,
+      Type::Unsigned16 => types::Type::Unsigned16,
+      Type::Unsigned32 => types::Type::Unsigned32,
+      Type::Unsigned64 => types::Type::Unsigned64,
+      Type::Signed8 => types::Type::Signed8,
+      Type::Signed16 => types::Type::Signed16,
+      Type::Signed32 => types::Type::Signed32,
+      Type::Signed64 => types::Type::Signed64,
+      Type::Float32 => types::Type::Float32,
+      Type::Float64 => types::Type::Float64,
+      Type::Text(size) => types::Type::Text(size),
+      Type::Blob(size) => types::Type::Blob(size),
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Column {
+  pub typ: Type,
+  pub nullable: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Table {
+  pub name: String,
+  pub columns: Vec<Column>,
+  pub column_map: FxHashMap<String, usize>,
+  pub fragmentation: Vec<u64>,
+  pub row_count: u64,
+}
+
+impl Table {
+  fn row_size(&self) -> usize {
+    self.columns.iter().map(|column| column.typ.byte_size()).sum()
+  }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct DbShape {
+  pub reclaim: VecDeque<u64>,
+  pub table_map: FxHashMap<String, usize>,
+  pub tables: Vec<Table>,
+}
+
+/// v0 -> current: the tables are created again and their rows re-inserted, through the current code
+pub fn upgrade(image: &mut Vec<u8>) -> Result<()> {
+  let header: DbHeader = bincode::deserialize(&image[..SECTOR_SIZE])?;
+  ensure!(header.looks_valid(image.len()), "invalid v0 header");
+  //the last sector was usually cut short back then
+  image.resize(header.sector_count as usize * SECTOR_SIZE, 0);
+  let (shape_start, shape_end) = header.shape_location;
+  let shape: DbShape = match shape_start == shape_end {
+    true => DbShape::default(),
+    false => bincode::deserialize(&image[(shape_start as usize * SECTOR_SIZE)..(shape_end as usize * SECTOR_SIZE)])
+      .context("invalid v0 shape")?,
+  };
+
+  let mut db = Database::new(Cursor::new(vec![]))?;
+  //tables are created in their original order, so pointers keep pointing to the same ones
+  for table in &shape.tables {
+    let mut names: Vec<(&String, &usize)> = table.column_map.iter().collect();
+    names.sort_unstable_by_key(|&(_, &idx)| idx);
+    ensure!(names.len() == table.columns.len(), "invalid columns of table {}", table.name);
+    let columns = names.into_iter().zip(&table.columns).map(|((name, _), column)| DbColumn {
+      name: name.clone(),
+      typ: DbTypeExt::Type(column.typ.upgrade()),
+      nullable: column.nullable,
+      default: None,
+      auto_increment: false,
+      on_delete: None,
+    }).collect();
+    db.perform(DbOperation::TableCreate { name: table.name.clone(), columns })?;
+
+    //rows of tables with nullable columns start with a null bitmap now, nothing was NULL back then
+    let null_bitmap = vec![0; table.columns.iter().filter(|column| column.nullable).count().div_ceil(8)];
+    let row_size = table.row_size();
+    ensure!(table.row_count == 0 || (1..=SECTOR_SIZE).contains(&row_size), "invalid row size of table {}", table.name);
+    let rows_per_fragment = (SECTOR_SIZE / row_size.max(1)) as u64;
+    for row in 0..table.row_count {
+      let sector = *table.fragmentation
+        .get((row / rows_per_fragment) as usize)
+        .with_context(|| format!("row {row} of table {} is missing", table.name))?;
+      let start = sector as usize * SECTOR_SIZE + (row % rows_per_fragment) as usize * row_size;
+      ensure!(start + row_size <= image.len(), "row {row} of table {} is out of bounds", table.name);
+      db.table_insert(&table.name, &[&null_bitmap[..], &image[start..(start + row_size)]].concat())?;
+    }
+  }
+  db.sync_database()?;
+  *image = db.into_files().0.into_inner();
+  Ok(())
+}
@@ -1,12 +1,20 @@
 //! public json api to the database
 
-use serde::{Serialize, Deserialize};
+use std::{fmt, time::{SystemTime, UNIX_EPOCH}};
+use serde::{Serialize, Deserialize, Deserializer, de::{self, Visitor, SeqAccess}};
 use rustc_hash::FxHashMap;
 use anyhow::{Result, Context, ensure, bail};
 use crate::{
-  database::{Database, RwData, SECTOR_SIZE},
-  shape::{Table, Column, DbShape},
-  types::{Type, ReprSize, TypeTree, TextType, IntegerType, IntegerSize, FloatType, FloatSize},
+  database::{Database, RwData, Snapshot},
+  transaction::{Transaction, TransactionId},
+  shape::{Table, Column, DbShape, ColumnDefault, DefaultValue, OnDelete, MAX_ROW_SIZE},
+  types::{Type, ReprSize, TypeTree, TextType, BlobType, IntegerType, IntegerSize, FloatType, FloatSize},
+  header::Features,
+  heap::HeapRef,
+  freemap::FreeMap,
+  query::{DbPredicate, DbOrderKey},
+  aggregate::DbAggregate,
+  integrity::ensure_target,
 };
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -35,17 +43,100 @@ pub struct DbColumn {
 
   #[serde(default)]
   pub nullable: bool,
+
+  #[serde(default)]
+  pub default: Option<DbDefault>,
+
+  /// integer columns only
+  #[serde(default)]
+  pub auto_increment: bool,
+
+  /// pointer columns only, `Restrict` by default
+  #[serde(default)]
+  pub on_delete: Option<OnDelete>,
 }
 
+/// `{"Value": ...}`, `"Now"` or `"Sequence"`
 #[derive(Serialize, Deserialize)]
+pub enum DbDefault {
+  Value(DbRowColumnValue),
+  Now,
+  Sequence,
+}
+
+/// Blobs are arrays of bytes, integers of any width (and sign) fit into `Integer`
+#[derive(Serialize, Clone)]
 #[serde(untagged)]
 pub enum DbRowColumnValue {
+  /// JSON `null`
+  Null,
   String(String),
   Blob(Vec<u8>),
   Integer(i128),
   Float(f64),
 }
 
+/// Not derived, as untagged enums can't deserialize `i128`
+impl<'de> Deserialize<'de> for DbRowColumnValue {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+    deserializer.deserialize_any(DbRowColumnValueVisitor)
+  }
+}
+
+struct DbRowColumnValueVisitor;
+
+impl<'de> Visitor<'de> for DbRowColumnValueVisitor {
+  type Value = DbRowColumnValue;
+
+  fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+    formatter.write_str("null, a number, a string or an array of bytes")
+  }
+
+  fn visit_unit<E: de::Error>(self) -> std::result::Result<Self::Value, E> {
+    Ok(DbRowColumnValue::Null)
+  }
+
+  fn visit_none<E: de::Error>(self) -> std::result::Result<Self::Value, E> {
+    Ok(DbRowColumnValue::Null)
+  }
+
+  fn visit_i64<E: de::Error>(self, v: i64) -> std::result::Result<Self::Value, E> {
+    Ok(DbRowColumnValue::Integer(v.into()))
+  }
+
+  fn visit_u64<E: de::Error>(self, v: u64) -> std::result::Result<Self::Value, E> {
+    Ok(DbRowColumnValue::Integer(v.into()))
+  }
+
+  fn visit_i128<E: de::Error>(self, v: i128) -> std::result::Result<Self::Value, E> {
+    Ok(DbRowColumnValue::Integer(v))
+  }
+
+  fn visit_f64<E: de::Error>(self, v: f64) -> std::result::Result<Self::Value, E> {
+    Ok(DbRowColumnValue::Float(v))
+  }
+
+  fn visit_str<E: de::Error>(self, v: &str) -> std::result::Result<Self::Value, E> {
+    Ok(DbRowColumnValue::String(v.to_owned()))
+  }
+
+  fn visit_string<E: de::Error>(self, v: String) -> std::result::Result<Self::Value, E> {
+    Ok(DbRowColumnValue::String(v))
+  }
+
+  fn visit_bytes<E: de::Error>(self, v: &[u8]) -> std::result::Result<Self::Value, E> {
+    Ok(DbRowColumnValue::Blob(v.to_vec()))
+  }
+
+  fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> std::result::Result<Self::Value, A::Error> {
+    let mut blob = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+    while let Some(byte) = seq.next_element::<u8>()? {
+      blob.push(byte);
+    }
+    Ok(DbRowColumnValue::Blob(blob))
+  }
+}
+
 macro_rules! impl_to_bytes_as_num {
   // (_0 $self:ident f32) => {
   //   { let DbRowColumnValue::Float(i) = ($self) else { bail!("expected float") }; i }
@@ -68,6 +159,17 @@ macro_rules! impl_to_bytes_as_num {
 }
 
 impl DbRowColumnValue {
+  /// Name of the kind of the value, for error messages
+  pub fn kind(&self) -> &'static str {
+    match self {
+      Self::Null => "null",
+      Self::String(_) => "string",
+      Self::Blob(_) => "blob",
+      Self::Integer(_) => "integer",
+      Self::Float(_) => "float",
+    }
+  }
+
   pub fn serialize_as_type(&self, typ: Type) -> Result<Box<[u8]>> {
     match typ.into_type_tree() {
       TypeTree::Number(nt) => match nt {
@@ -82,13 +184,20 @@ impl DbRowColumnValue {
           IntegerType { size: IntegerSize::Int64, is_signed: true } => impl_to_bytes_as_num!(self, i64),
         },
         crate::types::NumberType::Float(FloatType { size }) => {
-          let DbRowColumnValue::Float(f) = self else { bail!("expected float") };
+          //integers are fine too, json doesn't tell `1` and `1.0` apart
+          let f = match self {
+            DbRowColumnValue::Float(f) => *f,
+            DbRowColumnValue::Integer(i) => *i as f64,
+            _ => bail!("expected float"),
+          };
           match size {
-            FloatSize::Float32 => Ok(Box::new((*f as f32).to_le_bytes())),
+            FloatSize::Float32 => Ok(Box::new((f as f32).to_le_bytes())),
             FloatSize::Float64 => Ok(Box::new(f.to_le_bytes())),
           }
         }
       },
+      //row id in the target table
+      TypeTree::Pointer(_) => impl_to_bytes_as_num!(self, u64),
       TypeTree::Text(TextType { size }) => {
         let Self::String(s) = self else { bail!("expected string") };
         if s.len() > size { bail!("string is too long") };
@@ -96,26 +205,149 @@ impl DbRowColumnValue {
           (s.len() as u32).to_le_bytes().iter()
             .chain(s.as_bytes().iter())
             .copied()
-            .chain(std::iter::repeat(0).take(size - s.len()))
+            .chain(std::iter::repeat_n(0, size - s.len()))
             .collect()
         )
       },
-      _ => todo!("parse other types")
+      TypeTree::Blob(BlobType { size }) => {
+        let Self::Blob(b) = self else { bail!("expected blob") };
+        ensure!(b.len() == size, "expected a blob of {} bytes, got {}", size, b.len());
+        Ok(b.clone().into_boxed_slice())
+      },
+      TypeTree::VarText(_) | TypeTree::VarBlob(_) => unreachable!("heap values are not stored in the row"),
+    }
+  }
+
+  /// Contents of a value stored in the heap (`VarText`/`VarBlob`)
+  fn heap_bytes(&self, typ: Type) -> Result<&[u8]> {
+    match (typ, self) {
+      (Type::VarText, Self::String(s)) => Ok(s.as_bytes()),
+      (Type::VarText, _) => bail!("expected string"),
+      (Type::VarBlob, Self::Blob(b)) => Ok(b),
+      (Type::VarBlob, _) => bail!("expected blob"),
+      _ => unreachable!("not a heap type"),
+    }
+  }
+
+  fn from_heap_bytes(typ: Type, data: Vec<u8>) -> Result<Self> {
+    match typ {
+      Type::VarText => Ok(Self::String(String::from_utf8(data).context("invalid utf8")?)),
+      Type::VarBlob => Ok(Self::Blob(data)),
+      _ => unreachable!("not a heap type"),
     }
   }
 
   fn deserialize_as_type(typ: Type, data: &[u8]) -> Result<Self> {
-    match typ.into_type_tree() {
-      TypeTree::Text(_) => {
-        let len = u32::from_le_bytes(data[..4].try_into().context("invalid data length")?) as usize;
-        let s = String::from_utf8(data[4..(4 + len)].to_vec()).context("invalid utf8")?;
-        Ok(Self::String(s))
+    ensure!(data.len() == typ.into_type_tree().byte_size(), "invalid data length");
+    Ok(match typ {
+      Type::Unsigned8 => Self::Integer(data[0].into()),
+      Type::Unsigned16 => Self::Integer(u16::from_le_bytes(data.try_into()?).into()),
+      Type::Unsigned32 => Self::Integer(u32::from_le_bytes(data.try_into()?).into()),
+      Type::Unsigned64 | Type::Pointer(_) => Self::Integer(u64::from_le_bytes(data.try_into()?).into()),
+      Type::Signed8 => Self::Integer((data[0] as i8).into()),
+      Type::Signed16 => Self::Integer(i16::from_le_bytes(data.try_into()?).into()),
+      Type::Signed32 => Self::Integer(i32::from_le_bytes(data.try_into()?).into()),
+      Type::Signed64 => Self::Integer(i64::from_le_bytes(data.try_into()?).into()),
+      Type::Float32 => Self::Float(f32::from_le_bytes(data.try_into()?).into()),
+      Type::Float64 => Self::Float(f64::from_le_bytes(data.try_into()?)),
+      Type::Text(size) => {
+        let len = u32::from_le_bytes(data[..4].try_into()?) as usize;
+        ensure!(len <= size, "invalid text length");
+        Self::String(String::from_utf8(data[4..(4 + len)].to_vec()).context("invalid utf8")?)
       },
-      _ => todo!("parse other types")
+      Type::Blob(_) => Self::Blob(data.to_vec()),
+      Type::VarText | Type::VarBlob => unreachable!("heap values are not stored in the row"),
+    })
+  }
+}
+
+impl From<DefaultValue> for DbRowColumnValue {
+  fn from(value: DefaultValue) -> Self {
+    match value {
+      DefaultValue::Integer(i) => Self::Integer(i),
+      DefaultValue::Float(f) => Self::Float(f),
+      DefaultValue::String(s) => Self::String(s),
+      DefaultValue::Blob(b) => Self::Blob(b),
     }
   }
 }
 
+impl TryFrom<DbRowColumnValue> for DefaultValue {
+  type Error = anyhow::Error;
+
+  fn try_from(value: DbRowColumnValue) -> Result<Self> {
+    Ok(match value {
+      DbRowColumnValue::Null => bail!("default value can't be null"),
+      DbRowColumnValue::Integer(i) => Self::Integer(i),
+      DbRowColumnValue::Float(f) => Self::Float(f),
+      DbRowColumnValue::String(s) => Self::String(s),
+      DbRowColumnValue::Blob(b) => Self::Blob(b),
+    })
+  }
+}
+
+impl DbColumn {
+  /// `table` is the name of the table being created, its columns can point at it too
+  fn resolve(self, shape: &DbShape, table: &str) -> Result<Column> {
+    let typ = match &self.typ {
+      //the new table is appended, so it's going to get the next index
+      DbTypeExt::UnresolvedPointer(name) if name == table => Type::Pointer(shape.tables.len() as u32),
+      typ => typ.resolve(shape).context("Failed to resolve pointer or type")?,
+    };
+    if let Type::Pointer(target) = typ {
+      ensure!(
+        target as usize <= shape.tables.len(),
+        "column `{}` points at a table that doesn't exist ({target})", self.name
+      );
+    }
+    ensure!(!self.auto_increment || typ.is_integer(), "auto_increment column `{}` must be an integer", self.name);
+    let default = match self.default {
+      None => None,
+      Some(_) if self.auto_increment => bail!("auto_increment column `{}` can't have a default", self.name),
+      Some(DbDefault::Value(value)) => {
+        //make sure it can actually be stored in the column
+        if typ.is_heap() {
+          value.heap_bytes(typ)?;
+        } else {
+          value.serialize_as_type(typ)?;
+        }
+        Some(ColumnDefault::Value(value.try_into()?))
+      },
+      Some(DbDefault::Now) | Some(DbDefault::Sequence) if !typ.is_integer() => {
+        bail!("generated default of column `{}` needs an integer column", self.name)
+      },
+      Some(DbDefault::Now) => Some(ColumnDefault::Now),
+      Some(DbDefault::Sequence) => Some(ColumnDefault::Sequence),
+    };
+    let on_delete = self.on_delete.unwrap_or_default();
+    ensure!(
+      self.on_delete.is_none() || matches!(typ, Type::Pointer(_)),
+      "on_delete of column `{}` only works on pointer columns", self.name
+    );
+    ensure!(
+      on_delete != OnDelete::SetNull || self.nullable,
+      "on_delete of column `{}` can't be SetNull, as it's not nullable", self.name
+    );
+    Ok(Column {
+      typ,
+      nullable: self.nullable,
+      default,
+      auto_increment: self.auto_increment,
+      on_delete,
+    })
+  }
+}
+
+/// Fails for NULL values in columns that are not nullable
+fn ensure_nullable(table: &Table, column: usize, value: &DbRowColumnValue) -> Result<()> {
+  ensure!(
+    !matches!(value, DbRowColumnValue::Null) || table.columns[column].nullable,
+    "column `{}` can't be null",
+    table.column_names()[column]
+  );
+  Ok(())
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum DbRow {
@@ -144,13 +376,79 @@ pub enum DbOperation {
     name: String,
     columns: DbRow,
   },
+  /// Update the row with the same value in the `key` column, or insert it if there's none\
+  /// Unlike a query followed by an insert, nothing else can happen in between
+  TableUpsert {
+    name: String,
+    key: String,
+    columns: DbRow,
+  },
+  /// Read a single row, or (without `_rowid`) every row matching `where`\
+  /// The scan results are sorted by `order_by`, and `limit`/`offset` pick a part of them
   TableQuery {
     name: String,
     columns: Vec<DbQueryKey>,
-    _rowid: u64
+    #[serde(default)]
+    _rowid: Option<u64>,
+    #[serde(rename = "where", default)]
+    filter: Option<DbPredicate>,
+    #[serde(default)]
+    order_by: Vec<DbOrderKey>,
+    #[serde(default)]
+    limit: Option<u64>,
+    #[serde(default)]
+    offset: u64,
+  },
+  /// COUNT/SUM/MIN/MAX/AVG over the rows matching `where`, per group of rows with the same `group_by` values
+  TableAggregate {
+    name: String,
+    aggregates: Vec<DbAggregate>,
+    #[serde(default)]
+    group_by: Vec<DbQueryKey>,
+    #[serde(rename = "where", default)]
+    filter: Option<DbPredicate>,
+    #[serde(default)]
+    having: Option<DbPredicate>,
   },
   TableDelete {
     name: String
+  },
+  /// Change values of some columns of the row with the id, or of every row matching `where`\
+  /// Other columns are left as they are, updating a row that doesn't exist does nothing
+  TableUpdate {
+    name: String,
+    set: FxHashMap<String, DbRowColumnValue>,
+    #[serde(default)]
+    _rowid: Option<u64>,
+    #[serde(rename = "where", default)]
+    filter: Option<DbPredicate>,
+  },
+  /// Delete the row with the id, or every row matching `where`\
+  /// The ids of other rows don't change (but the deleted ids may be reused by later inserts), deleting a row that doesn't exist does nothing
+  RowDelete {
+    name: String,
+    #[serde(default)]
+    _rowid: Option<u64>,
+    #[serde(rename = "where", default)]
+    filter: Option<DbPredicate>,
+  },
+  /// Defragment and compact the database
+  Optimize,
+  /// Start a transaction, following operations (and requests with the returned id) run inside it
+  Begin,
+  Commit,
+  Rollback,
+  Savepoint {
+    name: String
+  },
+  RollbackTo {
+    name: String
+  },
+}
+
+impl DbOperation {
+  pub fn is_read_only(&self) -> bool {
+    matches!(self, DbOperation::TableQuery { .. } | DbOperation::TableAggregate { .. })
   }
 }
 
@@ -158,15 +456,377 @@ pub enum DbOperation {
 pub enum DbOperationResult {
   NoResult,
   TableQuery(Vec<Vec<DbRowColumnValue>>),
+  TableScan(Vec<DbScanRow>),
+  Transaction(TransactionId),
+  /// number of rows changed by the operation
+  Affected(u64),
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DbScanRow {
+  pub _rowid: u64,
+  pub columns: Vec<DbRowColumnValue>,
+}
+
+/// Transaction state while a request is being performed
+#[derive(Default)]
+struct RequestContext {
+  /// transaction the request is currently working on
+  transaction: Option<(TransactionId, Transaction)>,
+  /// state of the main database, stashed away while working on a transaction
+  main_state: Option<Snapshot>,
+  main_modified: bool,
 }
 
 impl<T: RwData> Database<T> {
-  pub fn perform_multiple(&mut self, ops: Vec<DbOperation>) -> Result<Vec<DbOperationResult>> {
+  /// Perform a request, optionally as a part of an open transaction\
+  /// The request itself is atomic: if any operation fails, both the main state and the transaction are left untouched\
+  /// Returns the id of the transaction that's still open after the request (if any)
+  pub fn perform_request(&mut self, transaction: Option<TransactionId>, ops: Vec<DbOperation>) -> Result<(Vec<DbOperationResult>, Option<TransactionId>)> {
+    self.transactions.expire();
+
+    let mut ctx = RequestContext::default();
+    if let Some(id) = transaction {
+      let mut transaction = self.transactions
+        .take(id)
+        .context("transaction not found (it might have been rolled back after being idle for too long)")?;
+      ctx.main_state = Some(self.replace_state(std::mem::take(&mut transaction.state)));
+      ctx.transaction = Some((id, transaction));
+      self.active_transaction = Some(id);
+    }
+
+    //save everything needed to undo the request
+    let main_start = ctx.main_state.clone().unwrap_or_else(|| self.snapshot());
+    let transaction_start = ctx.transaction.as_ref().map(|(id, transaction)| {
+      let mut transaction = transaction.clone();
+      transaction.state = self.snapshot();
+      (*id, transaction)
+    });
+    let version_start = self.transactions.version;
+
     let mut results = vec![];
     for op in ops {
-      results.push(self.perform(op)?);
+      match self.perform_in_context(&mut ctx, op) {
+        Ok(result) => results.push(result),
+        Err(err) => {
+          self.restore(main_start);
+          self.transactions.version = version_start;
+          self.active_transaction = None;
+          if let Some((id, transaction)) = transaction_start {
+            self.transactions.put_back(id, transaction);
+          }
+          self.transactions.forget_closed();
+          return Err(err)
+        }
+      }
+    }
+
+    if ctx.main_modified {
+      self.transactions.version += 1;
     }
-    Ok(results)
+
+    //switch back to the main state
+    let open_transaction = ctx.transaction.map(|(id, mut transaction)| {
+      transaction.state = self.replace_state(ctx.main_state.take().unwrap());
+      self.transactions.put_back(id, transaction);
+      id
+    });
+    self.active_transaction = None;
+    self.transactions.forget_closed();
+
+    Ok((results, open_transaction))
+  }
+
+  fn perform_in_context(&mut self, ctx: &mut RequestContext, op: DbOperation) -> Result<DbOperationResult> {
+    match op {
+      DbOperation::Begin => {
+        ensure!(ctx.transaction.is_none(), "a transaction is already in progress");
+        //operations before `Begin` in the same request happened before the transaction
+        if ctx.main_modified {
+          self.transactions.version += 1;
+          ctx.main_modified = false;
+        }
+        let (id, transaction) = self.transactions.begin();
+        ctx.main_state = Some(self.snapshot());
+        ctx.transaction = Some((id, transaction));
+        self.active_transaction = Some(id);
+        Ok(DbOperationResult::Transaction(id))
+      },
+      DbOperation::Commit => {
+        let (id, transaction) = ctx.transaction.take().context("no transaction in progress")?;
+        ensure!(
+          transaction.base_version == self.transactions.version,
+          "transaction {id} conflicts with changes committed after it began, roll it back and try again"
+        );
+        //the transaction state becomes the main state
+        ctx.main_state = None;
+        ctx.main_modified = true;
+        self.active_transaction = None;
+        Ok(DbOperationResult::NoResult)
+      },
+      DbOperation::Rollback => {
+        ctx.transaction.take().context("no transaction in progress")?;
+        self.restore(ctx.main_state.take().unwrap());
+        self.active_transaction = None;
+        Ok(DbOperationResult::NoResult)
+      },
+      DbOperation::Savepoint { name } => {
+        let (_, transaction) = ctx.transaction.as_mut().context("no transaction in progress")?;
+        transaction.savepoints.push((name, self.snapshot()));
+        Ok(DbOperationResult::NoResult)
+      },
+      DbOperation::RollbackTo { name } => {
+        let (_, transaction) = ctx.transaction.as_mut().context("no transaction in progress")?;
+        let position = transaction.savepoints
+          .iter()
+          .rposition(|(savepoint_name, _)| *savepoint_name == name)
+          .context("savepoint not found")?;
+        //the savepoint itself stays, so it's possible to roll back to it again
+        transaction.savepoints.truncate(position + 1);
+        self.restore(transaction.savepoints[position].1.clone());
+        Ok(DbOperationResult::NoResult)
+      },
+      op => {
+        if ctx.transaction.is_none() && !op.is_read_only() {
+          ctx.main_modified = true;
+        }
+        self.perform(op)
+      }
+    }
+  }
+
+  /// Bytes stored in the row for the value\
+  /// Values of heap columns are written to the heap first, the row only gets a reference to them
+  fn store_value(&mut self, typ: Type, value: &DbRowColumnValue) -> Result<Box<[u8]>> {
+    if typ.is_heap() {
+      Ok(Box::new(self.heap_write(value.heap_bytes(typ)?)?.to_bytes()))
+    } else {
+      value.serialize_as_type(typ)
+    }
+  }
+
+  /// Counterpart of `store_value`
+  pub(crate) fn load_value(&mut self, name: &str, row: u64, column: usize) -> Result<DbRowColumnValue> {
+    let typ = self.shape.get_table(name).unwrap().columns[column].typ;
+    if self.table_is_null(name, row, column)? {
+      return Ok(DbRowColumnValue::Null)
+    }
+    let data = self.table_read_row_column(name, row, column)?;
+    if typ.is_heap() {
+      let data = self.heap_read(HeapRef::from_bytes(&data))?;
+      DbRowColumnValue::from_heap_bytes(typ, data)
+    } else {
+      DbRowColumnValue::deserialize_as_type(typ, &data)
+    }
+  }
+
+  /// Value for a column left out of an insert, `None` if it doesn't have a default\
+  /// Auto-increment columns and `Sequence` defaults take the next value of the table's counter
+  fn generate_default(&mut self, name: &str, column: usize) -> Option<DbRowColumnValue> {
+    let table = self.shape.get_table_mut(name).unwrap();
+    if table.columns[column].auto_increment {
+      return Some(DbRowColumnValue::Integer(table.next_counter() as i128))
+    }
+    match table.columns[column].default.clone()? {
+      ColumnDefault::Value(value) => Some(value.into()),
+      ColumnDefault::Now => {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs());
+        Some(DbRowColumnValue::Integer(now as i128))
+      },
+      ColumnDefault::Sequence => Some(DbRowColumnValue::Integer(table.next_counter() as i128)),
+    }
+  }
+
+  /// Value of the column (or the column a pointer key leads to) in the row\
+  /// Every column of a pointer key except for the last one is a pointer, followed to the row in the target table\
+  /// NULL and dangling pointers (to deleted rows or tables) give NULL
+  pub(crate) fn query_key(&mut self, name: &str, row: u64, key: &DbQueryKey) -> Result<DbRowColumnValue> {
+    let path = match key {
+      DbQueryKey::Simple(key_name) => std::slice::from_ref(key_name),
+      DbQueryKey::Pointer(path) => &path[..],
+    };
+    let (column_name, pointers) = path.split_last().context("empty pointer key")?;
+    let mut table_name = name.to_string();
+    let mut row = row;
+    for pointer in pointers {
+      let table = self.shape.get_table(&table_name).context("table not found")?;
+      let Some(&col_idx) = table.column_map.get(pointer) else {
+        bail!("column `{pointer}` not found");
+      };
+      let Type::Pointer(target) = table.columns[col_idx].typ else {
+        bail!("column `{pointer}` is not a pointer");
+      };
+      let DbRowColumnValue::Integer(target_row) = self.load_value(&table_name, row, col_idx)? else {
+        return Ok(DbRowColumnValue::Null)
+      };
+      let Some(target_table) = self.shape.tables.get(target as usize) else {
+        return Ok(DbRowColumnValue::Null)
+      };
+      let target_row = target_row as u64;
+      if target_row >= target_table.row_count || target_table.is_deleted(target_row) {
+        return Ok(DbRowColumnValue::Null)
+      }
+      table_name = target_table.name.clone();
+      row = target_row;
+    }
+    let table = self.shape.get_table(&table_name).context("table not found")?;
+    let Some(&col_idx) = table.column_map.get(column_name) else {
+      bail!("column `{column_name}` not found");
+    };
+    self.load_value(&table_name, row, col_idx)
+  }
+
+  /// Values in the column order\
+  /// Columns with a default and nullable columns can be omitted from named rows\
+  /// NULL in an auto-increment column is replaced with the next value of the counter
+  fn row_values(&mut self, name: &str, row: DbRow) -> Result<Vec<DbRowColumnValue>> {
+    let table = self.shape.get_table(name).unwrap();
+    let mut values: Vec<Option<DbRowColumnValue>> = match row {
+      DbRow::AsPositional(values) => {
+        ensure!(
+          values.len() == table.columns.len(),
+          "expected {} values, got {}",
+          table.columns.len(), values.len()
+        );
+        values.into_iter().map(Some).collect()
+      },
+      DbRow::AsNamed(mut values) => {
+        if let Some(unknown) = values.keys().find(|key| !table.column_map.contains_key(*key)) {
+          bail!("column `{unknown}` not found");
+        }
+        table.column_names()
+          .into_iter()
+          .map(|column_name| values.remove(column_name))
+          .collect()
+      },
+    };
+    for (idx, value) in values.iter_mut().enumerate() {
+      let table = self.shape.get_table_mut(name).unwrap();
+      let column = &table.columns[idx];
+      match value {
+        Some(DbRowColumnValue::Null) if column.auto_increment => *value = None,
+        Some(DbRowColumnValue::Integer(i)) if column.auto_increment => table.counter_past(*i),
+        _ => (),
+      }
+      if value.is_none() {
+        *value = self.generate_default(name, idx);
+      }
+    }
+    let table = self.shape.get_table(name).unwrap();
+    table.column_names()
+      .into_iter()
+      .zip(&table.columns)
+      .zip(values)
+      .map(|((column_name, column), value)| match value {
+        Some(value) => Ok(value),
+        None if column.nullable => Ok(DbRowColumnValue::Null),
+        None => bail!("missing value for column `{column_name}`"),
+      })
+      .collect()
+  }
+
+  fn insert_row(&mut self, name: &str, row: DbRow) -> Result<u64> {
+    let values = self.row_values(name, row)?;
+    let table = self.shape.get_table(name).unwrap();
+    for (idx, value) in values.iter().enumerate() {
+      ensure_nullable(table, idx, value)?;
+      ensure_target(&self.shape, table, idx, value)?;
+    }
+
+    //Create buffer to write
+    let mut row_buffer = vec![0; table.byte_size()].into_boxed_slice();
+    let table_columns = table.columns.clone();
+    let null_bits: Vec<Option<usize>> = (0..table_columns.len()).map(|idx| table.null_bit(idx)).collect();
+    let mut position = table.null_bitmap_size();
+    for ((column, value), null_bit) in table_columns.iter().zip(&values).zip(null_bits) {
+      let value_len = column.typ.into_type_tree().byte_size();
+      let value_range = position..(position + value_len);
+      position += value_len;
+      //NULL values are all zeroes, with the bit in the null bitmap set
+      if let (DbRowColumnValue::Null, Some(bit)) = (value, null_bit) {
+        row_buffer[bit / 8] |= 1 << (bit % 8);
+        continue
+      }
+      let value_buf = self.store_value(column.typ, value)?;
+      ensure!(value_buf.len() == value_len, "invalid length");
+      row_buffer[value_range].copy_from_slice(&value_buf[..]);
+    }
+
+    let row = self.table_insert(name, &row_buffer)?;
+    self.mark_shape_dirty();
+    Ok(row)
+  }
+
+  /// First row with the value in the column (there are no indexes, so this scans the whole table)
+  /// NULL is not equal to anything (not even NULL)
+  fn find_row(&mut self, name: &str, column: usize, value: &DbRowColumnValue) -> Result<Option<u64>> {
+    if let DbRowColumnValue::Null = value {
+      return Ok(None)
+    }
+    let table = self.shape.get_table(name).unwrap();
+    let typ = table.columns[column].typ;
+    let rows: Vec<u64> = (0..table.row_count).filter(|&row| !table.is_deleted(row)).collect();
+    //heap values are compared by their contents, everything else by the stored bytes
+    let wanted: Box<[u8]> = if typ.is_heap() {
+      value.heap_bytes(typ)?.into()
+    } else {
+      value.serialize_as_type(typ)?
+    };
+    for row in rows {
+      if self.table_is_null(name, row, column)? {
+        continue
+      }
+      let mut data = self.table_read_row_column(name, row, column)?;
+      if typ.is_heap() {
+        data = self.heap_read(HeapRef::from_bytes(&data))?.into();
+      }
+      if data == wanted {
+        return Ok(Some(row))
+      }
+    }
+    Ok(None)
+  }
+
+  /// Rows an operation works on, picked by either a row id or a predicate\
+  /// An id of a row that doesn't exist (or was deleted) doesn't pick anything
+  fn pick_rows(&mut self, name: &str, row: Option<u64>, filter: Option<&DbPredicate>) -> Result<Vec<u64>> {
+    let table = self.shape.get_table(name).context("table not found")?;
+    match (row, filter) {
+      (Some(row), None) => Ok((row < table.row_count && !table.is_deleted(row)).then_some(row).into_iter().collect()),
+      (None, Some(filter)) => self.table_scan(name, Some(filter)),
+      _ => bail!("either `_rowid` or `where` is needed (but not both)"),
+    }
+  }
+
+  /// Only the bytes of the changed columns are rewritten\
+  /// Heap values are replaced, the old ones are freed
+  pub(crate) fn table_update_row(&mut self, name: &str, row: u64, changes: &[(usize, DbRowColumnValue)]) -> Result<()> {
+    for (column, value) in changes {
+      let table = self.shape.get_table(name).unwrap();
+      ensure_nullable(table, *column, value)?;
+      ensure_target(&self.shape, table, *column, value)?;
+      let typ = table.columns[*column].typ;
+      let nullable = table.columns[*column].nullable;
+      //NULL heap values are empty references, so this works for them too
+      if typ.is_heap() {
+        let old_ref = HeapRef::from_bytes(&self.table_read_row_column(name, row, *column)?);
+        self.heap_free(old_ref)?;
+      }
+      let data = match value {
+        DbRowColumnValue::Null => vec![0; typ.into_type_tree().byte_size()].into_boxed_slice(),
+        value => self.store_value(typ, value)?,
+      };
+      self.table_write_row_column(name, row, *column, &data)?;
+      if nullable {
+        self.table_set_null(name, row, *column, matches!(value, DbRowColumnValue::Null))?;
+      }
+      let table = self.shape.get_table_mut(name).unwrap();
+      if let (true, DbRowColumnValue::Integer(i)) = (table.columns[*column].auto_increment, value) {
+        table.counter_past(*i);
+      }
+    }
+    self.mark_shape_dirty();
+    Ok(())
   }
 
   pub fn perform(&mut self, op: DbOperation) -> Result<DbOperationResult> {
@@ -177,12 +837,6 @@ impl<T: RwData> Database<T> {
         }
         let table = Table {
           name: name.clone(),
-          columns: columns.iter().map(|c| -> Result<Column> {
-            Ok(Column {
-              typ: c.typ.resolve(&self.shape).context("Failed to resolve pointer or type")?,
-              nullable: c.nullable,
-            })
-          }).collect::<Result<Vec<Column>>>()?,
           column_map: {
             let mut map = FxHashMap::default();
             for (idx, column) in columns.iter().enumerate() {
@@ -190,82 +844,448 @@ impl<T: RwData> Database<T> {
             }
             map
           },
+          columns: columns.into_iter().map(|c| c.resolve(&self.shape, &name)).collect::<Result<Vec<Column>>>()?,
           fragmentation: Vec::new(),
           row_count: 0,
+          deleted: FreeMap::default(),
+          counter: 1,
         };
-        if table.byte_size() > SECTOR_SIZE {
-          bail!("row size is too big. compile with larger sector size or reduce row size");
+        match table.checked_byte_size() {
+          Some(0) => bail!("table must have at least one column"),
+          Some(size) if size <= MAX_ROW_SIZE => (),
+          _ => bail!("row size is too big, it can be at most {} bytes", MAX_ROW_SIZE),
+        }
+        if table.columns.iter().any(|column| column.typ.is_heap()) && !self.header.features.contains(Features::HEAP) {
+          self.header.features.insert(Features::HEAP);
+          self.mark_header_dirty();
         }
         self.shape.insert_table(&name, table);
         self.mark_shape_dirty();
         Ok(DbOperationResult::NoResult)
       },
       DbOperation::TableInsert { name, columns } => {
-        let table = self.shape.get_table_mut(&name).context("table not found")?;
-
-        //Get sorted list of values
-        //TODO allow omitting nullable in AsNamed
-        let values = match columns {
-          DbRow::AsNamed(columns) => todo!("handle DbRow::AsNamed"),
-          DbRow::AsPositional(columns) => columns,
+        self.shape.get_table(&name).context("table not found")?;
+        self.insert_row(&name, columns)?;
+        Ok(DbOperationResult::NoResult)
+      },
+      DbOperation::TableUpsert { name, key, columns } => {
+        let table = self.shape.get_table(&name).context("table not found")?;
+        let Some(&key_idx) = table.column_map.get(&key) else {
+          bail!("key column not found");
+        };
+        //the key is looked up first, as values of omitted columns are only needed for inserts
+        let key_value = match &columns {
+          DbRow::AsPositional(values) => {
+            ensure!(
+              values.len() == table.columns.len(),
+              "expected {} values, got {}",
+              table.columns.len(), values.len()
+            );
+            values[key_idx].clone()
+          },
+          DbRow::AsNamed(values) => values.get(&key).cloned().context("missing value for the key column")?,
         };
-        ensure!(values.len() == table.columns.len());
-
-        //Create buffer to write
-        let mut row_buffer = vec![0; table.byte_size()].into_boxed_slice();
-        let mut position = 0;
-        for (idx, value) in values.iter().enumerate() {
-          let column = &table.columns[idx];
-          let value_len = column.typ.into_type_tree().byte_size();
-          let value_range = position..(position + value_len);
-          let value_buf = value.serialize_as_type(column.typ)?;
-          ensure!(value_buf.len() == value_len, "invalid length");
-          row_buffer[value_range].copy_from_slice(&value_buf[..]);
-          position += value_len;
+        match self.find_row(&name, key_idx, &key_value)? {
+          Some(row) => {
+            //like TableUpdate, columns left out of a named row stay as they are
+            let table = self.shape.get_table(&name).unwrap();
+            let changes: Vec<(usize, DbRowColumnValue)> = match columns {
+              DbRow::AsPositional(values) => values.into_iter().enumerate().collect(),
+              DbRow::AsNamed(values) => values
+                .into_iter()
+                .map(|(column_name, value)| match table.column_map.get(&column_name) {
+                  Some(&idx) => Ok((idx, value)),
+                  None => bail!("column `{column_name}` not found"),
+                })
+                .collect::<Result<_>>()?,
+            };
+            //NULL only asks for a generated value in auto-increment columns, which the row has already
+            let changes: Vec<_> = changes
+              .into_iter()
+              .filter(|(idx, value)| {
+                *idx != key_idx && !(table.columns[*idx].auto_increment && matches!(value, DbRowColumnValue::Null))
+              })
+              .collect();
+            self.table_update_row(&name, row, &changes)?;
+          },
+          None => {
+            self.insert_row(&name, columns)?;
+          },
         }
-
-        self.table_insert(&name, &row_buffer)?;
-
-        self.mark_shape_dirty();
-
-        Ok(DbOperationResult::NoResult)
+        Ok(DbOperationResult::Affected(1))
       },
-      DbOperation::TableQuery { name, columns, _rowid } => {
-        let table_idx = *self.shape.table_map.get(&name).context("table not found")?;
-        let mut res = Vec::with_capacity(1);
+      DbOperation::TableQuery { name, columns, _rowid: Some(row), filter, order_by, limit, offset } => {
+        self.shape.get_table(&name).context("table not found")?;
+        ensure!(
+          filter.is_none() && order_by.is_empty() && limit.is_none() && offset == 0,
+          "`where`, `order_by`, `limit` and `offset` can't be used together with `_rowid`"
+        );
+        let mut res = Vec::with_capacity(columns.len());
         for key in columns.iter() {
-          match key {
-            DbQueryKey::Simple(key_name) => {
-              let table = &self.shape.tables[table_idx];
-              let Some(&col_idx) = table.column_map.get(key_name) else {
-                bail!("column not found");
-              };
-              let column = &table.columns[col_idx];
-              let column_type = column.typ;
-              match column_type.into_type_tree() {
-                TypeTree::Text(_) => {
-                  let roco_data = self.table_read_row_column(&name, _rowid, col_idx)?;
-                  let value = DbRowColumnValue::deserialize_as_type(column_type, &roco_data)?;
-                  res.push(value);
-                }
-                _ => todo!("handle other types"),
-              }
-            },
-            DbQueryKey::Pointer(_) => todo!("handle DbQueryKey::Pointer"),
-          }
+          res.push(self.query_key(&name, row, key)?);
         }
         Ok(DbOperationResult::TableQuery(vec![res]))
       },
+      DbOperation::TableQuery { name, columns, _rowid: None, filter, order_by, limit, offset } => {
+        let mut rows = vec![];
+        self.sorted_scan(&name, filter.as_ref(), &order_by, offset, limit, |db, row| {
+          let mut res = Vec::with_capacity(columns.len());
+          for key in columns.iter() {
+            res.push(db.query_key(&name, row, key)?);
+          }
+          rows.push(DbScanRow { _rowid: row, columns: res });
+          Ok(())
+        })?;
+        Ok(DbOperationResult::TableScan(rows))
+      },
+      DbOperation::TableAggregate { name, aggregates, group_by, filter, having } => {
+        let rows = self.table_aggregate(&name, &group_by, &aggregates, filter.as_ref(), having.as_ref())?;
+        Ok(DbOperationResult::TableQuery(rows))
+      },
       DbOperation::TableDelete { name } => {
-        //TODO move into function
-        let table = self.shape.tables.remove(*self.shape.table_map.get(&name).context("table not found")?);
-        self.shape.table_map.remove(&name);
-        for sector in table.fragmentation {
-          self.reclaim_sector(sector);
+        self.delete_table_references(&name)?;
+        self.table_heap_free(&name)?;
+        let table = self.shape.remove_table(&name).context("table not found")?;
+        //fragments that were given back don't have any sectors
+        for sector in table.fragmentation.into_iter().filter(|&sector| sector != 0) {
+          self.reclaim_sector(sector)?;
         }
         self.mark_shape_dirty();
         Ok(DbOperationResult::NoResult)
-      }
+      },
+      DbOperation::TableUpdate { name, set, _rowid, filter } => {
+        let table = self.shape.get_table(&name).context("table not found")?;
+        let mut changes = Vec::with_capacity(set.len());
+        for (column_name, value) in set {
+          let Some(&col_idx) = table.column_map.get(&column_name) else {
+            bail!("column not found");
+          };
+          changes.push((col_idx, value));
+        }
+        //rows are picked before any of them changes, so the update can't affect which ones match
+        let rows = self.pick_rows(&name, _rowid, filter.as_ref())?;
+        for &row in &rows {
+          self.table_update_row(&name, row, &changes)?;
+        }
+        Ok(DbOperationResult::Affected(rows.len() as u64))
+      },
+      DbOperation::RowDelete { name, _rowid, filter } => {
+        //matching rows are deleted together, so ON DELETE sees all of them at once
+        let rows = self.pick_rows(&name, _rowid, filter.as_ref())?;
+        self.delete_rows(&name, &rows)?;
+        Ok(DbOperationResult::Affected(rows.len() as u64))
+      },
+      DbOperation::Optimize => {
+        self.optimize()?;
+        Ok(DbOperationResult::NoResult)
+      },
+      DbOperation::Begin |
+      DbOperation::Commit |
+      DbOperation::Rollback |
+      DbOperation::Savepoint { .. } |
+      DbOperation::RollbackTo { .. } => {
+        bail!("transaction control operations can't be used here")
+      },
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::{json, Value};
+  use crate::{shape::MAX_ROW_SIZE, testing::{MemoryDb, memory_db, request}};
+  use super::{DbOperation, DbRow, DbRowColumnValue};
+
+  #[test]
+  fn failed_requests_are_rolled_back() {
+    let mut db = memory_db();
+    let create = json!({"type": "TableCreate", "name": "users", "columns": [{"name": "name", "type": {"Text": 8}}]});
+    let insert = json!({"type": "TableInsert", "name": "users", "columns": ["alice"]});
+    let missing = json!({"type": "TableDelete", "name": "missing"});
+
+    assert!(request(&mut db, json!([create, insert, missing])).is_err());
+    assert!(db.shape.get_table("users").is_none());
+
+    request(&mut db, json!([create])).unwrap();
+    assert!(request(&mut db, json!([insert, missing])).is_err());
+    assert_eq!(db.shape.get_table("users").unwrap().row_count, 0);
+  }
+  #[test]
+  fn rows_wider_than_a_sector_span_several_sectors() {
+    let mut db = memory_db();
+    request(&mut db, json!([
+      {"type": "TableCreate", "name": "wide", "columns": [{"name": "a", "type": {"Text": 1500}}, {"name": "b", "type": {"Text": 8}}]},
+      {"type": "TableInsert", "name": "wide", "columns": ["first", "1"]},
+      {"type": "TableInsert", "name": "wide", "columns": ["second", "2"]},
+    ])).unwrap();
+    assert_eq!(db.shape.get_table("wide").unwrap().fragmentation.len(), 4);
+    let result = request(&mut db, json!([{"type": "TableQuery", "name": "wide", "columns": ["a", "b"], "_rowid": 1}])).unwrap();
+    assert_eq!(result, json!([{"TableQuery": [["second", "2"]]}]));
+    assert!(db.check(false).unwrap().issues.is_empty());
+  }
+
+  #[test]
+  fn rows_wider_than_the_limit_are_rejected() {
+    let mut db = memory_db();
+    let too_wide = json!([{"type": "TableCreate", "name": "a", "columns": [{"name": "x", "type": {"Blob": MAX_ROW_SIZE + 1}}]}]);
+    assert!(request(&mut db, too_wide).is_err());
+    //the size of the row must not overflow either
+    let overflowing = json!([{"type": "TableCreate", "name": "a", "columns": [
+      {"name": "x", "type": {"Blob": usize::MAX}},
+      {"name": "y", "type": {"Text": usize::MAX}},
+    ]}]);
+    assert!(request(&mut db, overflowing).is_err());
+    assert!(db.shape.get_table("a").is_none());
+  }
+  #[test]
+  fn deleted_slots_are_reused() {
+    let mut db = memory_db();
+    request(&mut db, json!([
+      {"type": "TableCreate", "name": "a", "columns": [{"name": "x", "type": {"Text": 8}}]},
+      {"type": "TableInsert", "name": "a", "columns": ["0"]},
+      {"type": "TableInsert", "name": "a", "columns": ["1"]},
+      {"type": "TableInsert", "name": "a", "columns": ["2"]},
+    ])).unwrap();
+    let result = request(&mut db, json!([
+      {"type": "RowDelete", "name": "a", "_rowid": 1},
+      {"type": "RowDelete", "name": "a", "_rowid": 1},
+      {"type": "RowDelete", "name": "a", "_rowid": 10},
+    ])).unwrap();
+    assert_eq!(result, json!([{"Affected": 1}, {"Affected": 0}, {"Affected": 0}]));
+    assert!(request(&mut db, json!([{"type": "TableQuery", "name": "a", "columns": ["x"], "_rowid": 1}])).is_err());
+
+    request(&mut db, json!([{"type": "TableInsert", "name": "a", "columns": ["new"]}])).unwrap();
+    let table = db.shape.get_table("a").unwrap();
+    assert_eq!(table.row_count, 3);
+    assert!(!table.is_deleted(1));
+    let result = request(&mut db, json!([{"type": "TableQuery", "name": "a", "columns": ["x"], "_rowid": 1}])).unwrap();
+    assert_eq!(result, json!([{"TableQuery": [["new"]]}]));
+    //deleting the last rows shrinks the table
+    request(&mut db, json!([{"type": "RowDelete", "name": "a", "_rowid": 2}])).unwrap();
+    assert_eq!(db.shape.get_table("a").unwrap().row_count, 2);
+    assert!(db.check(false).unwrap().issues.is_empty());
+  }
+  #[test]
+  fn table_update_rewrites_only_the_set_columns() {
+    let mut db = memory_db();
+    request(&mut db, json!([
+      {"type": "TableCreate", "name": "a", "columns": [{"name": "x", "type": {"Text": 8}}, {"name": "note", "type": "VarText"}]},
+      {"type": "TableInsert", "name": "a", "columns": ["0", "first"]},
+      {"type": "TableInsert", "name": "a", "columns": ["1", "second"]},
+      {"type": "RowDelete", "name": "a", "_rowid": 0},
+    ])).unwrap();
+    let result = request(&mut db, json!([
+      {"type": "TableUpdate", "name": "a", "set": {"note": "changed"}, "_rowid": 1},
+      {"type": "TableUpdate", "name": "a", "set": {"note": "deleted"}, "_rowid": 0},
+      {"type": "TableUpdate", "name": "a", "set": {"note": "missing"}, "_rowid": 7},
+      {"type": "TableQuery", "name": "a", "columns": ["x", "note"], "_rowid": 1},
+    ])).unwrap();
+    assert_eq!(result, json!([{"Affected": 1}, {"Affected": 0}, {"Affected": 0}, {"TableQuery": [["1", "changed"]]}]));
+    assert!(request(&mut db, json!([{"type": "TableUpdate", "name": "a", "set": {"y": "0"}, "_rowid": 1}])).is_err());
+    //the old heap value was freed
+    assert!(db.check(false).unwrap().issues.is_empty());
+  }
+  #[test]
+  fn rows_are_picked_by_predicate() {
+    let mut db = memory_db();
+    request(&mut db, json!([
+      {"type": "TableCreate", "name": "a", "columns": [{"name": "x", "type": "Signed32"}, {"name": "note", "type": "VarText"}]},
+      {"type": "TableInsert", "name": "a", "columns": [1, "one"]},
+      {"type": "TableInsert", "name": "a", "columns": [2, "two"]},
+      {"type": "TableInsert", "name": "a", "columns": [3, "three"]},
+      {"type": "TableInsert", "name": "a", "columns": [4, "four"]},
+    ])).unwrap();
+    let result = request(&mut db, json!([
+      {"type": "TableUpdate", "name": "a", "set": {"note": "big"}, "where": {"Gt": ["x", 2]}},
+      {"type": "RowDelete", "name": "a", "where": {"Like": ["note", "t%"]}},
+      {"type": "RowDelete", "name": "a", "where": {"Gt": ["x", 10]}},
+      {"type": "TableQuery", "name": "a", "columns": ["x", "note"]},
+    ])).unwrap();
+    assert_eq!(result, json!([
+      {"Affected": 2},
+      {"Affected": 1},
+      {"Affected": 0},
+      {"TableScan": [{"_rowid": 0, "columns": [1, "one"]}, {"_rowid": 2, "columns": [3, "big"]}, {"_rowid": 3, "columns": [4, "big"]}]},
+    ]));
+    //either an id or a predicate is needed, but not both
+    assert!(request(&mut db, json!([{"type": "RowDelete", "name": "a"}])).is_err());
+    assert!(request(&mut db, json!([{"type": "RowDelete", "name": "a", "_rowid": 0, "where": {"IsNull": "x"}}])).is_err());
+    assert!(request(&mut db, json!([{"type": "TableUpdate", "name": "a", "set": {"x": 0}}])).is_err());
+    assert!(db.check(false).unwrap().issues.is_empty());
+  }
+  #[test]
+  fn upsert_updates_the_row_with_the_same_key() {
+    let mut db = memory_db();
+    request(&mut db, json!([
+      {"type": "TableCreate", "name": "u", "columns": [{"name": "name", "type": "VarText"}, {"name": "visits", "type": {"Text": 8}}]},
+      {"type": "TableInsert", "name": "u", "columns": ["a", "1"]},
+    ])).unwrap();
+    let result = request(&mut db, json!([
+      {"type": "TableUpsert", "name": "u", "key": "name", "columns": ["a", "2"]},
+      {"type": "TableUpsert", "name": "u", "key": "name", "columns": ["b", "1"]},
+      {"type": "TableQuery", "name": "u", "columns": ["name", "visits"], "_rowid": 0},
+      {"type": "TableQuery", "name": "u", "columns": ["name", "visits"], "_rowid": 1},
+    ])).unwrap();
+    assert_eq!(result, json!([{"Affected": 1}, {"Affected": 1}, {"TableQuery": [["a", "2"]]}, {"TableQuery": [["b", "1"]]}]));
+    assert_eq!(db.shape.get_table("u").unwrap().row_count, 2);
+    assert!(request(&mut db, json!([{"type": "TableUpsert", "name": "u", "key": "nope", "columns": ["a", "3"]}])).is_err());
+    assert!(request(&mut db, json!([{"type": "TableUpsert", "name": "u", "key": "name", "columns": ["a"]}])).is_err());
+  }
+  #[test]
+  fn named_rows_are_resolved_by_column_name() {
+    let mut db = memory_db();
+    request(&mut db, json!([
+      {"type": "TableCreate", "name": "a", "columns": [
+        {"name": "x", "type": {"Text": 8}},
+        {"name": "note", "type": "VarText", "nullable": true},
+      ]},
+      {"type": "TableInsert", "name": "a", "columns": {"note": "first", "x": "0"}},
+      {"type": "TableInsert", "name": "a", "columns": {"x": "1"}},
+    ])).unwrap();
+    let result = request(&mut db, json!([
+      {"type": "TableQuery", "name": "a", "columns": ["x", "note"], "_rowid": 0},
+      {"type": "TableQuery", "name": "a", "columns": ["x", "note"], "_rowid": 1},
+    ])).unwrap();
+    assert_eq!(result, json!([{"TableQuery": [["0", "first"]]}, {"TableQuery": [["1", null]]}]));
+    assert!(request(&mut db, json!([{"type": "TableInsert", "name": "a", "columns": {"x": "2", "nope": ""}}])).is_err());
+    assert!(request(&mut db, json!([{"type": "TableInsert", "name": "a", "columns": {"note": "no x"}}])).is_err());
+    assert_eq!(db.shape.get_table("a").unwrap().row_count, 2);
+  }
+
+  #[test]
+  fn upsert_only_changes_given_columns() {
+    let mut db = memory_db();
+    request(&mut db, json!([
+      {"type": "TableCreate", "name": "u", "columns": [
+        {"name": "name", "type": "VarText"},
+        {"name": "visits", "type": {"Text": 8}},
+        {"name": "note", "type": "VarText", "nullable": true},
+      ]},
+      {"type": "TableInsert", "name": "u", "columns": {"name": "a", "visits": "1", "note": "kept"}},
+      {"type": "TableUpsert", "name": "u", "key": "name", "columns": {"name": "a", "visits": "5"}},
+      {"type": "TableUpsert", "name": "u", "key": "name", "columns": {"name": "b", "visits": "1"}},
+    ])).unwrap();
+    let result = request(&mut db, json!([
+      {"type": "TableQuery", "name": "u", "columns": ["name", "visits", "note"], "_rowid": 0},
+      {"type": "TableQuery", "name": "u", "columns": ["name", "visits", "note"], "_rowid": 1},
+    ])).unwrap();
+    assert_eq!(result, json!([{"TableQuery": [["a", "5", "kept"]]}, {"TableQuery": [["b", "1", null]]}]));
+    assert!(request(&mut db, json!([{"type": "TableUpsert", "name": "u", "key": "name", "columns": {"name": "a", "nope": "1"}}])).is_err());
+    assert!(request(&mut db, json!([{"type": "TableUpsert", "name": "u", "key": "name", "columns": {"visits": "1"}}])).is_err());
+  }
+  #[test]
+  fn null_bitmap_spans_several_bytes() {
+    let mut db = memory_db();
+    //every other column is nullable, so the bitmap needs two bytes
+    let columns: Vec<Value> = (0..20)
+      .map(|i| json!({"name": format!("c{i}"), "type": {"Text": 4}, "nullable": i % 2 == 0}))
+      .collect();
+    let values: Vec<Value> = (0..20).map(|i| if i % 4 == 0 { json!(null) } else { json!(i.to_string()) }).collect();
+    request(&mut db, json!([
+      {"type": "TableCreate", "name": "wide", "columns": columns},
+      {"type": "TableInsert", "name": "wide", "columns": values},
+    ])).unwrap();
+    assert_eq!(db.shape.get_table("wide").unwrap().null_bitmap_size(), 2);
+    let names: Vec<String> = (0..20).map(|i| format!("c{i}")).collect();
+    let result = request(&mut db, json!([{"type": "TableQuery", "name": "wide", "columns": names, "_rowid": 0}])).unwrap();
+    assert_eq!(result, json!([{"TableQuery": [values]}]));
+
+    //setting a value clears its bit, and only its bit
+    request(&mut db, json!([{"type": "TableUpdate", "name": "wide", "set": {"c16": "7", "c2": null}, "_rowid": 0}])).unwrap();
+    let result = request(&mut db, json!([{"type": "TableQuery", "name": "wide", "columns": ["c0", "c2", "c6", "c16"], "_rowid": 0}])).unwrap();
+    assert_eq!(result, json!([{"TableQuery": [[null, null, "6", "7"]]}]));
+    let err = request(&mut db, json!([{"type": "TableUpdate", "name": "wide", "set": {"c1": null}, "_rowid": 0}])).unwrap_err();
+    assert!(err.to_string().contains("can't be null"), "{err}");
+  }
+  #[test]
+  fn explicit_values_move_the_counter_past_them() {
+    let mut db = memory_db();
+    request(&mut db, json!([
+      {"type": "TableCreate", "name": "u", "columns": [
+        {"name": "id", "type": "Unsigned32", "auto_increment": true},
+        {"name": "name", "type": "VarText"},
+        {"name": "visits", "type": {"Text": 8}, "default": {"Value": "0"}},
+      ]},
+      {"type": "TableInsert", "name": "u", "columns": {"name": "a"}},
+    ])).unwrap();
+    let id = |db: &mut MemoryDb, row| u32::from_le_bytes((*db.table_read_row_column("u", row, 0).unwrap()).try_into().unwrap());
+    let counter = |db: &MemoryDb| db.shape.get_table("u").unwrap().counter;
+    assert_eq!((id(&mut db, 0), counter(&db)), (1, 2));
+
+    let insert = |id| DbOperation::TableInsert {
+      name: "u".into(),
+      columns: DbRow::AsPositional(vec![id, DbRowColumnValue::String("b".into()), DbRowColumnValue::String("1".into())]),
+    };
+    db.perform(insert(DbRowColumnValue::Integer(10))).unwrap();
+    assert_eq!(counter(&db), 11);
+    //smaller values don't move it back
+    db.perform(insert(DbRowColumnValue::Integer(5))).unwrap();
+    db.perform(insert(DbRowColumnValue::Null)).unwrap();
+    assert_eq!((id(&mut db, 3), counter(&db)), (11, 12));
+    //neither do updates that set a value, nor upserts that leave it out
+    db.perform(DbOperation::TableUpdate {
+      name: "u".into(),
+      set: [("id".to_string(), DbRowColumnValue::Integer(20))].into_iter().collect(),
+      _rowid: Some(1),
+      filter: None,
+    }).unwrap();
+    assert_eq!(counter(&db), 21);
+    request(&mut db, json!([{"type": "TableUpsert", "name": "u", "key": "name", "columns": {"name": "a", "id": null, "visits": "2"}}])).unwrap();
+    assert_eq!((id(&mut db, 0), counter(&db)), (1, 21));
+    let result = request(&mut db, json!([{"type": "TableQuery", "name": "u", "columns": ["visits"], "_rowid": 0}])).unwrap();
+    assert_eq!(result, json!([{"TableQuery": [["2"]]}]));
+  }
+  #[test]
+  fn every_column_type_is_read_back() {
+    let mut db = memory_db();
+    let types = json!(["Unsigned8", "Signed16", "Unsigned32", "Signed64", "Float32", "Float64", {"Text": 8}, {"Blob": 3}, "VarText", "VarBlob"]);
+    let columns: Vec<Value> = types.as_array().unwrap()
+      .iter()
+      .enumerate()
+      .map(|(i, typ)| json!({"name": format!("c{i}"), "type": typ}))
+      .collect();
+    let values = json!([255, -300, 70000, -5000000000i64, 1.5, 2, "text", [1, 2, 3], "heap", [4, 5]]);
+    request(&mut db, json!([
+      {"type": "TableCreate", "name": "t", "columns": columns},
+      {"type": "TableInsert", "name": "t", "columns": values},
+    ])).unwrap();
+    let names: Vec<String> = (0..10).map(|i| format!("c{i}")).collect();
+    let result = request(&mut db, json!([{"type": "TableQuery", "name": "t", "columns": names, "_rowid": 0}])).unwrap();
+    assert_eq!(result, json!([{"TableQuery": [[255, -300, 70000, -5000000000i64, 1.5, 2.0, "text", [1, 2, 3], "heap", [4, 5]]]}]));
+    //values have to fit in the column
+    for (column, value) in [("c0", json!(256)), ("c2", json!(-1)), ("c6", json!("too long!")), ("c7", json!([1]))] {
+      let update = json!([{"type": "TableUpdate", "name": "t", "set": {column: value}, "_rowid": 0}]);
+      assert!(request(&mut db, update).is_err(), "{column}");
     }
   }
+
+  #[test]
+  fn pointer_keys_are_followed() {
+    let mut db = memory_db();
+    request(&mut db, json!([
+      {"type": "TableCreate", "name": "c", "columns": [{"name": "name", "type": "VarText"}]},
+      {"type": "TableCreate", "name": "o", "columns": [
+        {"name": "n", "type": "Unsigned8"},
+        {"name": "customer", "type": {"Pointer": "c"}, "nullable": true},
+      ]},
+      {"type": "TableInsert", "name": "c", "columns": ["alice"]},
+      {"type": "TableInsert", "name": "c", "columns": ["bob"]},
+      {"type": "TableInsert", "name": "o", "columns": [1, 1]},
+      {"type": "TableInsert", "name": "o", "columns": [2, 0]},
+      {"type": "TableInsert", "name": "o", "columns": [3, null]},
+    ])).unwrap();
+    let result = request(&mut db, json!([
+      {"type": "TableQuery", "name": "o", "columns": ["n", ["customer", "name"]], "order_by": [{"key": ["customer", "name"]}]},
+      {"type": "TableQuery", "name": "o", "columns": ["n"], "where": {"Eq": [["customer", "name"], "bob"]}},
+    ])).unwrap();
+    assert_eq!(result, json!([
+      {"TableScan": [
+        {"_rowid": 1, "columns": [2, "alice"]},
+        {"_rowid": 0, "columns": [1, "bob"]},
+        {"_rowid": 2, "columns": [3, null]},
+      ]},
+      {"TableScan": [{"_rowid": 0, "columns": [1]}]},
+    ]));
+    assert!(request(&mut db, json!([{"type": "TableQuery", "name": "o", "columns": [["n", "name"]], "_rowid": 0}])).is_err());
+  }
 }
@@ -0,0 +1,775 @@
+//! filtered table scans and sorting
+
+use std::{
+  cmp::Ordering,
+  io::{self, Read},
+};
+use serde::{Serialize, Deserialize};
+use anyhow::{Result, Context, bail};
+use crate::{
+  database::{Database, RwData},
+  operations::{DbRowColumnValue, DbQueryKey},
+};
+
+/// Condition on the values of a row, for example `{"And": [{"Gt": ["age", 18]}, {"Like": ["name", "J%"]}]}`\
+/// Like in SQL, comparisons with NULL are unknown (neither true nor false), so even `Not` doesn't match them
+#[derive(Serialize, Deserialize)]
+pub enum DbPredicate {
+  Eq(DbQueryKey, DbRowColumnValue),
+  Ne(DbQueryKey, DbRowColumnValue),
+  Lt(DbQueryKey, DbRowColumnValue),
+  Le(DbQueryKey, DbRowColumnValue),
+  Gt(DbQueryKey, DbRowColumnValue),
+  Ge(DbQueryKey, DbRowColumnValue),
+  And(Vec<DbPredicate>),
+  Or(Vec<DbPredicate>),
+  Not(Box<DbPredicate>),
+  In(DbQueryKey, Vec<DbRowColumnValue>),
+  /// both ends are included
+  Between(DbQueryKey, DbRowColumnValue, DbRowColumnValue),
+  IsNull(DbQueryKey),
+  /// `%` matches any number of characters, `_` exactly one
+  Like(DbQueryKey, String),
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum DbNulls {
+  First,
+  Last,
+}
+
+/// For example `{"key": "age", "desc": true, "nulls": "First"}`\
+/// By default NULL is larger than anything else, so it goes last in ascending order and first in descending
+#[derive(Serialize, Deserialize)]
+pub struct DbOrderKey {
+  pub key: DbQueryKey,
+  #[serde(default)]
+  pub desc: bool,
+  #[serde(default)]
+  pub nulls: Option<DbNulls>,
+}
+
+/// Bytes of sort keys kept in memory, larger sorts are split into sorted runs stored in temporary sectors, and merged
+#[cfg(not(test))]
+const SORT_MEMORY: usize = 4 << 20;
+#[cfg(test)]
+const SORT_MEMORY: usize = 2 << 10;
+
+/// Runs merged at once, more runs are merged in several passes
+#[cfg(not(test))]
+const MERGE_FAN_IN: usize = 64;
+#[cfg(test)]
+const MERGE_FAN_IN: usize = 3;
+
+/// `None` if either of the values is NULL\
+/// Integers and floats can be compared with each other, other values only with the same kind
+pub fn compare(a: &DbRowColumnValue, b: &DbRowColumnValue) -> Result<Option<Ordering>> {
+  use DbRowColumnValue as V;
+  Ok(Some(match (a, b) {
+    (V::Null, _) | (_, V::Null) => return Ok(None),
+    (V::Integer(a), V::Integer(b)) => a.cmp(b),
+    (V::Float(a), V::Float(b)) => a.total_cmp(b),
+    (V::Integer(a), V::Float(b)) => (*a as f64).total_cmp(b),
+    (V::Float(a), V::Integer(b)) => a.total_cmp(&(*b as f64)),
+    (V::String(a), V::String(b)) => a.cmp(b),
+    (V::Blob(a), V::Blob(b)) => a.cmp(b),
+    _ => bail!("can't compare {} with {}", a.kind(), b.kind()),
+  }))
+}
+
+fn like(text: &str, pattern: &str) -> bool {
+  let text: Vec<char> = text.chars().collect();
+  //text positions the part of the pattern matched so far can end at
+  let mut ends = vec![false; text.len() + 1];
+  ends[0] = true;
+  for p in pattern.chars() {
+    let mut next = vec![false; text.len() + 1];
+    for position in 0..=text.len() {
+      if !ends[position] {
+        continue
+      }
+      match p {
+        '%' => {
+          next[position..].fill(true);
+          break
+        },
+        '_' => if position < text.len() {
+          next[position + 1] = true;
+        },
+        c => if text.get(position) == Some(&c) {
+          next[position + 1] = true;
+        },
+      }
+    }
+    ends = next;
+  }
+  ends[text.len()]
+}
+
+/// Three-valued `and`, used for `Between`
+fn and(a: Option<bool>, b: Option<bool>) -> Option<bool> {
+  match (a, b) {
+    (Some(false), _) | (_, Some(false)) => Some(false),
+    (Some(true), Some(true)) => Some(true),
+    _ => None,
+  }
+}
+
+/// Order of two rows by their sort keys (in the `order_by` order)
+fn order(a: &[DbRowColumnValue], b: &[DbRowColumnValue], order_by: &[DbOrderKey]) -> Result<Ordering> {
+  for ((a, b), key) in a.iter().zip(b).zip(order_by) {
+    let nulls_first = key.nulls.map_or(key.desc, |nulls| nulls == DbNulls::First);
+    let ordering = match (a, b) {
+      (DbRowColumnValue::Null, DbRowColumnValue::Null) => Ordering::Equal,
+      (DbRowColumnValue::Null, _) => if nulls_first { Ordering::Less } else { Ordering::Greater },
+      (_, DbRowColumnValue::Null) => if nulls_first { Ordering::Greater } else { Ordering::Less },
+      (a, b) => {
+        let ordering = compare(a, b)?.unwrap();
+        if key.desc { ordering.reverse() } else { ordering }
+      },
+    };
+    if ordering.is_ne() {
+      return Ok(ordering)
+    }
+  }
+  Ok(Ordering::Equal)
+}
+
+/// Sort keys of a row, as stored in sort runs
+struct SortEntry {
+  keys: Vec<DbRowColumnValue>,
+  row: u64,
+}
+
+impl SortEntry {
+  /// The row id, then every key as a tag byte followed by the value (strings and blobs with their length first)
+  fn encode(&self, out: &mut Vec<u8>) {
+    out.extend_from_slice(&self.row.to_le_bytes());
+    for key in &self.keys {
+      match key {
+        DbRowColumnValue::Null => out.push(0),
+        DbRowColumnValue::Integer(i) => {
+          out.push(1);
+          out.extend_from_slice(&i.to_le_bytes());
+        },
+        DbRowColumnValue::Float(f) => {
+          out.push(2);
+          out.extend_from_slice(&f.to_le_bytes());
+        },
+        DbRowColumnValue::String(s) => {
+          out.push(3);
+          out.extend_from_slice(&(s.len() as u64).to_le_bytes());
+          out.extend_from_slice(s.as_bytes());
+        },
+        DbRowColumnValue::Blob(b) => {
+          out.push(4);
+          out.extend_from_slice(&(b.len() as u64).to_le_bytes());
+          out.extend_from_slice(b);
+        },
+      }
+    }
+  }
+
+  fn decode(input: &mut impl Read, key_count: usize) -> Result<Self> {
+    fn bytes<const N: usize>(input: &mut impl Read) -> Result<[u8; N]> {
+      let mut bytes = [0; N];
+      input.read_exact(&mut bytes)?;
+      Ok(bytes)
+    }
+    fn vec(input: &mut impl Read) -> Result<Vec<u8>> {
+      let mut data = vec![0; u64::from_le_bytes(bytes(input)?) as usize];
+      input.read_exact(&mut data)?;
+      Ok(data)
+    }
+    let row = u64::from_le_bytes(bytes(input)?);
+    let mut keys = Vec::with_capacity(key_count);
+    for _ in 0..key_count {
+      keys.push(match bytes::<1>(input)?[0] {
+        0 => DbRowColumnValue::Null,
+        1 => DbRowColumnValue::Integer(i128::from_le_bytes(bytes(input)?)),
+        2 => DbRowColumnValue::Float(f64::from_le_bytes(bytes(input)?)),
+        3 => DbRowColumnValue::String(String::from_utf8(vec(input)?).context("Invalid text in a sort run")?),
+        4 => DbRowColumnValue::Blob(vec(input)?),
+        tag => bail!("Invalid value tag {tag} in a sort run"),
+      });
+    }
+    Ok(Self { keys, row })
+  }
+
+  /// Roughly how much memory the entry takes
+  fn size(&self) -> usize {
+    size_of::<Self>() + self.keys.iter().map(|key| size_of::<DbRowColumnValue>() + match key {
+      DbRowColumnValue::String(s) => s.len(),
+      DbRowColumnValue::Blob(b) => b.len(),
+      _ => 0,
+    }).sum::<usize>()
+  }
+}
+
+/// Order of the entries by their keys, ties are broken by the row id, which keeps the sort stable
+fn order_entries(a: &SortEntry, b: &SortEntry, order_by: &[DbOrderKey]) -> Result<Ordering> {
+  Ok(order(&a.keys, &b.keys, order_by)?.then(a.row.cmp(&b.row)))
+}
+
+fn sort_entries(entries: &mut [SortEntry], order_by: &[DbOrderKey]) -> Result<()> {
+  let mut error = None;
+  entries.sort_unstable_by(|a, b| order_entries(a, b, order_by).unwrap_or_else(|err| {
+    error.get_or_insert(err);
+    Ordering::Equal
+  }));
+  match error {
+    Some(err) => Err(err),
+    None => Ok(()),
+  }
+}
+
+/// Sorted entries stored one after another in consecutive temporary sectors
+#[derive(Clone, Copy)]
+struct Run {
+  sector: u64,
+  /// bytes of entries, the last sector is padded with zeroes
+  len: u64,
+  count: u64,
+}
+
+/// Appends a run to temporary sectors, a sector at a time
+struct RunWriter {
+  run: Run,
+  written: u64,
+  buffer: Vec<u8>,
+}
+
+impl RunWriter {
+  fn new(sector: u64) -> Self {
+    Self { run: Run { sector, len: 0, count: 0 }, written: 0, buffer: vec![] }
+  }
+
+  fn push<T: RwData>(&mut self, db: &mut Database<T>, entry: &SortEntry) -> Result<()> {
+    let len = self.buffer.len();
+    entry.encode(&mut self.buffer);
+    self.run.len += (self.buffer.len() - len) as u64;
+    self.run.count += 1;
+    let sector_size = db.sector_size();
+    while self.buffer.len() >= sector_size {
+      db.write_temporary_sector(self.run.sector + self.written, &self.buffer[..sector_size])?;
+      self.buffer.drain(..sector_size);
+      self.written += 1;
+    }
+    Ok(())
+  }
+
+  fn finish<T: RwData>(mut self, db: &mut Database<T>) -> Result<Run> {
+    if !self.buffer.is_empty() {
+      self.buffer.resize(db.sector_size(), 0);
+      db.write_temporary_sector(self.run.sector + self.written, &self.buffer)?;
+    }
+    Ok(self.run)
+  }
+}
+
+/// Reads a run back, a sector at a time
+struct RunReader {
+  run: Run,
+  /// bytes of the run read into `buffer` so far
+  position: u64,
+  remaining: u64,
+  buffer: Box<[u8]>,
+  cursor: usize,
+}
+
+/// `Read` for the rest of a run, so entries can be decoded from it
+struct RunSource<'a, T: RwData> {
+  reader: &'a mut RunReader,
+  db: &'a mut Database<T>,
+}
+
+impl<T: RwData> Read for RunSource<'_, T> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let reader = &mut *self.reader;
+    if reader.cursor == reader.buffer.len() {
+      if reader.position == reader.run.len {
+        return Ok(0)
+      }
+      let sector_size = self.db.sector_size() as u64;
+      let sector = reader.run.sector + reader.position / sector_size;
+      let data = self.db.read_temporary_sector(sector).map_err(io::Error::other)?;
+      let len = (reader.run.len - reader.position).min(sector_size) as usize;
+      reader.buffer = data[..len].into();
+      reader.position += len as u64;
+      reader.cursor = 0;
+    }
+    let len = buf.len().min(reader.buffer.len() - reader.cursor);
+    buf[..len].copy_from_slice(&reader.buffer[reader.cursor..reader.cursor + len]);
+    reader.cursor += len;
+    Ok(len)
+  }
+}
+
+impl RunReader {
+  fn new(run: Run) -> Self {
+    Self { run, position: 0, remaining: run.count, buffer: Box::new([]), cursor: 0 }
+  }
+
+  fn next<T: RwData>(&mut self, db: &mut Database<T>, key_count: usize) -> Result<Option<SortEntry>> {
+    if self.remaining == 0 {
+      return Ok(None)
+    }
+    self.remaining -= 1;
+    SortEntry::decode(&mut RunSource { reader: self, db }, key_count).map(Some)
+  }
+}
+
+/// Merges runs, only the next entry of every run is in memory
+struct Merger {
+  readers: Vec<RunReader>,
+  heads: Vec<Option<SortEntry>>,
+}
+
+impl Merger {
+  fn new<T: RwData>(runs: &[Run], db: &mut Database<T>, key_count: usize) -> Result<Self> {
+    let mut readers: Vec<RunReader> = runs.iter().copied().map(RunReader::new).collect();
+    let mut heads = Vec::with_capacity(readers.len());
+    for reader in &mut readers {
+      heads.push(reader.next(db, key_count)?);
+    }
+    Ok(Self { readers, heads })
+  }
+
+  fn next<T: RwData>(&mut self, db: &mut Database<T>, order_by: &[DbOrderKey]) -> Result<Option<SortEntry>> {
+    let mut smallest: Option<usize> = None;
+    for (idx, head) in self.heads.iter().enumerate() {
+      let Some(entry) = head else { continue };
+      let is_smaller = match smallest {
+        Some(smallest) => order_entries(entry, self.heads[smallest].as_ref().unwrap(), order_by)?.is_lt(),
+        None => true,
+      };
+      if is_smaller {
+        smallest = Some(idx);
+      }
+    }
+    let Some(smallest) = smallest else {
+      return Ok(None)
+    };
+    let next = self.readers[smallest].next(db, order_by.len())?;
+    Ok(std::mem::replace(&mut self.heads[smallest], next))
+  }
+}
+
+/// Sorted runs in temporary sectors of the database (see `Database::temporary_sectors`)\
+/// Runs are only appended, sectors of runs that were merged are not reused until the whole spill is released
+struct Spill {
+  runs: Vec<Run>,
+  /// first sector past the runs
+  end: u64,
+  /// file length to go back to once the sort is done
+  data_len: u64,
+}
+
+impl Spill {
+  fn new<T: RwData>(db: &mut Database<T>) -> Result<Self> {
+    let (end, data_len) = db.temporary_sectors()?;
+    Ok(Self { runs: vec![], end, data_len })
+  }
+
+  fn add_run(&mut self, run: Run, sector_size: usize) {
+    self.end = run.sector + run.len.div_ceil(sector_size as u64);
+    self.runs.push(run);
+  }
+
+  fn write_run<T: RwData>(&mut self, db: &mut Database<T>, entries: &[SortEntry]) -> Result<()> {
+    let mut writer = RunWriter::new(self.end);
+    for entry in entries {
+      writer.push(db, entry)?;
+    }
+    let run = writer.finish(db)?;
+    self.add_run(run, db.sector_size());
+    Ok(())
+  }
+
+  /// Merge runs, `MERGE_FAN_IN` at a time, until they can be merged in one go\
+  /// Merged runs only keep their first `keep` entries
+  fn merge_down<T: RwData>(&mut self, db: &mut Database<T>, order_by: &[DbOrderKey], keep: u64) -> Result<()> {
+    while self.runs.len() > MERGE_FAN_IN {
+      let runs = std::mem::take(&mut self.runs);
+      for group in runs.chunks(MERGE_FAN_IN) {
+        let mut merger = Merger::new(group, db, order_by.len())?;
+        let mut writer = RunWriter::new(self.end);
+        while writer.run.count < keep {
+          let Some(entry) = merger.next(db, order_by)? else { break };
+          writer.push(db, &entry)?;
+        }
+        let run = writer.finish(db)?;
+        self.add_run(run, db.sector_size());
+      }
+    }
+    Ok(())
+  }
+}
+
+impl DbPredicate {
+  /// `None` if the result is unknown\
+  /// `value` gives the value of a key (in the row being tested)
+  pub fn evaluate(&self, value: &mut impl FnMut(&DbQueryKey) -> Result<DbRowColumnValue>) -> Result<Option<bool>> {
+    Ok(match self {
+      DbPredicate::Eq(key, other) => compare(&value(key)?, other)?.map(Ordering::is_eq),
+      DbPredicate::Ne(key, other) => compare(&value(key)?, other)?.map(Ordering::is_ne),
+      DbPredicate::Lt(key, other) => compare(&value(key)?, other)?.map(Ordering::is_lt),
+      DbPredicate::Le(key, other) => compare(&value(key)?, other)?.map(Ordering::is_le),
+      DbPredicate::Gt(key, other) => compare(&value(key)?, other)?.map(Ordering::is_gt),
+      DbPredicate::Ge(key, other) => compare(&value(key)?, other)?.map(Ordering::is_ge),
+      DbPredicate::And(predicates) => {
+        let mut result = Some(true);
+        for predicate in predicates {
+          match predicate.evaluate(value)? {
+            Some(false) => return Ok(Some(false)),
+            None => result = None,
+            Some(true) => (),
+          }
+        }
+        result
+      },
+      DbPredicate::Or(predicates) => {
+        let mut result = Some(false);
+        for predicate in predicates {
+          match predicate.evaluate(value)? {
+            Some(true) => return Ok(Some(true)),
+            None => result = None,
+            Some(false) => (),
+          }
+        }
+        result
+      },
+      DbPredicate::Not(predicate) => predicate.evaluate(value)?.map(|result| !result),
+      DbPredicate::In(key, values) => {
+        let current = value(key)?;
+        let mut result = Some(false);
+        for candidate in values {
+          match compare(&current, candidate)? {
+            Some(Ordering::Equal) => return Ok(Some(true)),
+            None => result = None,
+            Some(_) => (),
+          }
+        }
+        result
+      },
+      DbPredicate::Between(key, low, high) => {
+        let current = value(key)?;
+        and(
+          compare(&current, low)?.map(Ordering::is_ge),
+          compare(&current, high)?.map(Ordering::is_le),
+        )
+      },
+      DbPredicate::IsNull(key) => Some(matches!(value(key)?, DbRowColumnValue::Null)),
+      DbPredicate::Like(key, pattern) => match value(key)? {
+        DbRowColumnValue::Null => None,
+        DbRowColumnValue::String(text) => Some(like(&text, pattern)),
+        other => bail!("LIKE only works on text, got {}", other.kind()),
+      },
+    })
+  }
+}
+
+impl<T: RwData> Database<T> {
+  /// Call `visit` with the ids of the rows matching the predicate (every row without one), in order, until it returns `false`\
+  /// Goes through every fragment of the table, skipping the given-back ones
+  pub fn scan_rows(
+    &mut self,
+    name: &str,
+    predicate: Option<&DbPredicate>,
+    mut visit: impl FnMut(&mut Self, u64) -> Result<bool>,
+  ) -> Result<()> {
+    let sector_size = self.sector_size();
+    let table = self.shape.get_table(name).context("table not found")?;
+    let sectors_per_fragment = table.sectors_per_fragment(sector_size);
+    for fragment in 0..(table.fragmentation.len() / sectors_per_fragment) {
+      let table = self.shape.get_table(name).context("table not found")?;
+      if table.fragmentation[fragment * sectors_per_fragment] == 0 {
+        continue
+      }
+      for row in table.fragment_rows(fragment, sector_size) {
+        if self.shape.get_table(name).context("table not found")?.is_deleted(row) {
+          continue
+        }
+        if let Some(predicate) = predicate {
+          if predicate.evaluate(&mut |key| self.query_key(name, row, key))? != Some(true) {
+            continue
+          }
+        }
+        if !visit(self, row)? {
+          return Ok(())
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Ids of the rows matching the predicate, see `scan_rows`
+  pub fn table_scan(&mut self, name: &str, predicate: Option<&DbPredicate>) -> Result<Vec<u64>> {
+    let mut rows = vec![];
+    self.scan_rows(name, predicate, |_, row| {
+      rows.push(row);
+      Ok(true)
+    })?;
+    Ok(rows)
+  }
+
+  fn sort_entry(&mut self, name: &str, row: u64, order_by: &[DbOrderKey]) -> Result<SortEntry> {
+    let mut keys = Vec::with_capacity(order_by.len());
+    for key in order_by {
+      keys.push(self.query_key(name, row, &key.key)?);
+    }
+    Ok(SortEntry { keys, row })
+  }
+
+  /// Call `visit` with the ids of the rows matching the predicate, sorted by `order_by` (rows with equal keys stay in order),
+  /// skipping `offset` of them and stopping after `limit`\
+  /// The sort keys are collected while scanning, once they take up `SORT_MEMORY` they're sorted and written as a run
+  /// to temporary sectors past the end of the database file\
+  /// The runs are then merged, so memory use doesn't depend on the number of rows, and the temporary sectors are cut off again
+  pub fn sorted_scan(
+    &mut self,
+    name: &str,
+    predicate: Option<&DbPredicate>,
+    order_by: &[DbOrderKey],
+    offset: u64,
+    limit: Option<u64>,
+    mut visit: impl FnMut(&mut Self, u64) -> Result<()>,
+  ) -> Result<()> {
+    //only the first `keep` rows of every run can make it into the result
+    let keep = offset.saturating_add(limit.unwrap_or(u64::MAX));
+    if keep <= offset {
+      return Ok(())
+    }
+    if order_by.is_empty() {
+      let mut position = 0;
+      return self.scan_rows(name, predicate, |db, row| {
+        if position >= offset {
+          visit(db, row)?;
+        }
+        position += 1;
+        Ok(position < keep)
+      })
+    }
+
+    let mut batch = vec![];
+    let mut batch_size = 0;
+    let mut spill = None;
+    let scanned = self.scan_rows(name, predicate, |db, row| {
+      let entry = db.sort_entry(name, row, order_by)?;
+      batch_size += entry.size();
+      batch.push(entry);
+      if batch_size >= SORT_MEMORY {
+        sort_entries(&mut batch, order_by)?;
+        batch.truncate(keep.try_into().unwrap_or(usize::MAX));
+        batch_size = batch.iter().map(SortEntry::size).sum();
+        //with a small limit, dropping the rows past it is often enough
+        if batch_size >= SORT_MEMORY / 2 {
+          let spill = match &mut spill {
+            Some(spill) => spill,
+            None => spill.insert(Spill::new(db)?),
+          };
+          spill.write_run(db, &batch)?;
+          batch.clear();
+          batch_size = 0;
+        }
+      }
+      Ok(true)
+    });
+    let result = scanned.and_then(|()| {
+      sort_entries(&mut batch, order_by)?;
+      batch.truncate(keep.try_into().unwrap_or(usize::MAX));
+      let Some(spill) = &mut spill else {
+        for entry in batch.drain(..).skip(offset.try_into().unwrap_or(usize::MAX)) {
+          visit(self, entry.row)?;
+        }
+        return Ok(())
+      };
+      spill.write_run(self, &batch)?;
+      batch.clear();
+      spill.merge_down(self, order_by, keep)?;
+      let mut merger = Merger::new(&spill.runs, self, order_by.len())?;
+      let mut position = 0;
+      while position < keep {
+        let Some(entry) = merger.next(self, order_by)? else { break };
+        if position >= offset {
+          visit(self, entry.row)?;
+        }
+        position += 1;
+      }
+      Ok(())
+    });
+    //the temporary sectors are released even if the sort failed
+    if let Some(spill) = spill {
+      self.release_temporary_sectors(spill.data_len)?;
+    }
+    result
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use anyhow::Result;
+  use serde_json::{json, Value};
+  use crate::testing::{MemoryDb, memory_db, request, rows};
+  use super::*;
+
+  /// `a` is 5, `s` is "hello", anything else is NULL
+  fn evaluate(predicate: Value) -> Result<Option<bool>> {
+    let predicate: DbPredicate = serde_json::from_value(predicate).unwrap();
+    predicate.evaluate(&mut |key| Ok(match key {
+      DbQueryKey::Simple(name) if name == "a" => DbRowColumnValue::Integer(5),
+      DbQueryKey::Simple(name) if name == "s" => DbRowColumnValue::String("hello".to_string()),
+      _ => DbRowColumnValue::Null,
+    }))
+  }
+
+  #[test]
+  fn like_patterns() {
+    assert!(like("hello", "h%o"));
+    assert!(like("hello", "h_llo"));
+    assert!(like("hello", "%ll%"));
+    assert!(like("", "%"));
+    assert!(like("héllo", "h_llo"));
+    assert!(!like("hello", "h_lo"));
+    assert!(!like("hello", "hello_"));
+    assert!(!like("hello", "Hello"));
+  }
+
+  #[test]
+  fn comparisons_with_null_are_unknown() {
+    assert_eq!(evaluate(json!({"Eq": ["a", 5.0]})).unwrap(), Some(true));
+    assert_eq!(evaluate(json!({"Gt": ["n", 1]})).unwrap(), None);
+    assert_eq!(evaluate(json!({"Not": {"Gt": ["n", 1]}})).unwrap(), None);
+    assert_eq!(evaluate(json!({"Ne": ["a", null]})).unwrap(), None);
+    assert_eq!(evaluate(json!({"IsNull": "n"})).unwrap(), Some(true));
+    assert_eq!(evaluate(json!({"Like": ["n", "%"]})).unwrap(), None);
+    assert_eq!(evaluate(json!({"In": ["a", [1, null]]})).unwrap(), None);
+    assert_eq!(evaluate(json!({"In": ["a", [null, 5]]})).unwrap(), Some(true));
+    assert_eq!(evaluate(json!({"Between": ["a", 1, null]})).unwrap(), None);
+    assert_eq!(evaluate(json!({"Between": ["a", 6, null]})).unwrap(), Some(false));
+  }
+
+  #[test]
+  fn and_or_use_three_valued_logic() {
+    assert_eq!(evaluate(json!({"And": [{"Eq": ["a", 5]}, {"Gt": ["n", 1]}]})).unwrap(), None);
+    assert_eq!(evaluate(json!({"And": [{"Eq": ["a", 4]}, {"Gt": ["n", 1]}]})).unwrap(), Some(false));
+    assert_eq!(evaluate(json!({"Or": [{"Eq": ["a", 4]}, {"Gt": ["n", 1]}]})).unwrap(), None);
+    assert_eq!(evaluate(json!({"Or": [{"Gt": ["n", 1]}, {"Like": ["s", "h%"]}]})).unwrap(), Some(true));
+    assert_eq!(evaluate(json!({"And": []})).unwrap(), Some(true));
+    assert_eq!(evaluate(json!({"Or": []})).unwrap(), Some(false));
+  }
+
+  #[test]
+  fn mismatched_kinds_are_errors() {
+    assert!(evaluate(json!({"Eq": ["s", 1]})).is_err());
+    assert!(evaluate(json!({"Like": ["a", "5"]})).is_err());
+  }
+
+  /// `count` rows of numbers repeating every 37 rows (so there are ties), every 11th one is NULL
+  fn numbers(count: u64) -> (MemoryDb, Vec<Option<u64>>) {
+    let mut db = memory_db();
+    let values: Vec<Option<u64>> = (0..count).map(|i| (i % 11 != 5).then_some(i * 17 % 37)).collect();
+    let mut ops = vec![json!({"type": "TableCreate", "name": "n", "columns": [
+      {"name": "id", "type": "Unsigned32"},
+      {"name": "v", "type": "Unsigned32", "nullable": true},
+    ]})];
+    ops.extend(values.iter().enumerate().map(|(id, v)| json!({"type": "TableInsert", "name": "n", "columns": [id, v]})));
+    request(&mut db, Value::Array(ops)).unwrap();
+    (db, values)
+  }
+
+  fn query(db: &mut MemoryDb, query: Value) -> Vec<u64> {
+    let mut query = query;
+    query["type"] = json!("TableQuery");
+    query["name"] = json!("n");
+    query["columns"] = json!(["id"]);
+    let result = request(db, json!([query])).unwrap();
+    rows(&result[0]).iter().map(|row| row[0].as_u64().unwrap()).collect()
+  }
+
+  fn file_len(db: &mut MemoryDb) -> usize {
+    db.temporary_sectors().unwrap().1 as usize
+  }
+
+  #[test]
+  fn sorts_more_rows_than_fit_in_memory() {
+    let (mut db, values) = numbers(1000);
+    let len = file_len(&mut db);
+    //NULL goes last in ascending order, equal values stay in row order
+    let mut expected: Vec<u64> = (0..1000).collect();
+    expected.sort_by_key(|&id| (values[id as usize].is_none(), values[id as usize]));
+    assert_eq!(query(&mut db, json!({"order_by": [{"key": "v"}]})), expected);
+
+    let mut expected: Vec<u64> = (0..1000).collect();
+    expected.sort_by_key(|&id| (values[id as usize].is_some(), std::cmp::Reverse(values[id as usize]), id));
+    assert_eq!(query(&mut db, json!({"order_by": [{"key": "v", "desc": true}]})), expected);
+    //the runs were written past the end of the file, and cut off again
+    assert_eq!(file_len(&mut db), len);
+    assert!(db.check(false).unwrap().issues.is_empty());
+  }
+
+  #[test]
+  fn offset_and_limit_apply_after_sorting() {
+    let (mut db, values) = numbers(1000);
+    let mut expected: Vec<u64> = (0..1000).filter(|&id| values[id as usize].is_some_and(|v| v >= 10)).collect();
+    expected.sort_by_key(|&id| (values[id as usize], std::cmp::Reverse(id)));
+    let sorted = query(&mut db, json!({
+      "where": {"Ge": ["v", 10]},
+      "order_by": [{"key": "v"}, {"key": "id", "desc": true}],
+      "offset": 300,
+      "limit": 200,
+    }));
+    assert_eq!(sorted, expected[300..500]);
+    assert!(query(&mut db, json!({"order_by": [{"key": "v"}], "limit": 0})).is_empty());
+    assert_eq!(query(&mut db, json!({"offset": 998})), [998, 999]);
+    assert_eq!(query(&mut db, json!({"limit": 3})), [0, 1, 2]);
+  }
+
+  #[test]
+  fn runs_are_merged_in_several_passes() {
+    let mut db = memory_db();
+    let len = file_len(&mut db);
+    let order_by = vec![DbOrderKey { key: DbQueryKey::Simple("v".to_string()), desc: false, nulls: None }];
+    let mut spill = Spill::new(&mut db).unwrap();
+    //runs of 0, 7, 14.. then 1, 8, 15.. and so on
+    for start in 0..7 {
+      let entries: Vec<SortEntry> = (0..100)
+        .map(|i| SortEntry { keys: vec![DbRowColumnValue::Integer(start + i * 7)], row: 0 })
+        .collect();
+      spill.write_run(&mut db, &entries).unwrap();
+    }
+    spill.merge_down(&mut db, &order_by, 500).unwrap();
+    assert!(spill.runs.len() <= MERGE_FAN_IN);
+    let mut merger = Merger::new(&spill.runs, &mut db, 1).unwrap();
+    let mut merged = vec![];
+    //the last merge is not truncated, its caller stops at `keep`
+    while let Some(entry) = merger.next(&mut db, &order_by).unwrap() {
+      let DbRowColumnValue::Integer(value) = entry.keys[0] else { panic!("not an integer") };
+      merged.push(value);
+    }
+    assert_eq!(merged[..500], (0..500).collect::<Vec<i128>>());
+    assert!(file_len(&mut db) > len);
+    db.release_temporary_sectors(spill.data_len).unwrap();
+    assert_eq!(file_len(&mut db), len);
+  }
+
+  #[test]
+  fn sort_entries_survive_encoding() {
+    let entry = SortEntry {
+      keys: vec![
+        DbRowColumnValue::Null,
+        DbRowColumnValue::Integer(-5),
+        DbRowColumnValue::Float(1.5),
+        DbRowColumnValue::String("é".repeat(100)),
+        DbRowColumnValue::Blob(vec![1, 2, 3]),
+      ],
+      row: 42,
+    };
+    let mut data = vec![];
+    entry.encode(&mut data);
+    let decoded = SortEntry::decode(&mut data.as_slice(), 5).unwrap();
+    assert_eq!(decoded.row, 42);
+    assert_eq!(json!(decoded.keys), json!(entry.keys));
+    assert!(SortEntry::decode(&mut &data[..data.len() - 1], 5).is_err());
+  }
+}
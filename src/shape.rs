@@ -1,35 +1,166 @@
-use std::collections::VecDeque;
+use std::{cmp::Ordering, ops::Range, collections::BTreeMap};
 use serde::{Serialize, Deserialize};
 use rustc_hash::FxHashMap;
-use crate::types::{Type, ReprSize};
+use crate::{types::{Type, ReprSize}, freemap::FreeMap};
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Target of pointer columns whose table was deleted
+pub const DROPPED_TABLE: u32 = u32::MAX;
+
+/// Rows can span several sectors, but nothing sensible needs them wider than this
+pub const MAX_ROW_SIZE: usize = 1024 * 1024;
+
+/// Constant default value, checked against the column type when the table is created
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum DefaultValue {
+  Integer(i128),
+  Float(f64),
+  String(String),
+  Blob(Vec<u8>),
+}
+
+/// Value used for the column when an insert leaves it out
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum ColumnDefault {
+  Value(DefaultValue),
+  /// unix time of the insert (in seconds), integer columns only
+  Now,
+  /// next value of the table's counter, integer columns only
+  Sequence,
+}
+
+/// What happens to the rows pointing at a row when it's deleted (or its table is)
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OnDelete {
+  /// the delete fails
+  #[default]
+  Restrict,
+  /// they're deleted too
+  Cascade,
+  /// the pointer becomes NULL, nullable columns only
+  SetNull,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Column {
   pub typ: Type,
   pub nullable: bool,
+  pub default: Option<ColumnDefault>,
+  /// values are taken from the table's counter if left out (or NULL), explicit values move the counter past them
+  pub auto_increment: bool,
+  /// only matters for pointer columns
+  pub on_delete: OnDelete,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Table {
   pub name: String,
   pub columns: Vec<Column>,
   pub column_map: FxHashMap<String, usize>,
+  /// sectors of every fragment, in order\
+  /// fragments without any rows left are given back, their sectors are 0
   pub fragmentation: Vec<u64>,
+  /// number of row slots (including the deleted ones), row ids are slot positions
   pub row_count: u64,
+  /// deleted row slots, reused by inserts
+  pub deleted: FreeMap,
+  /// next value for auto-increment columns and `Sequence` defaults
+  pub counter: u64,
+}
+
+impl Table {
+  /// Same as `byte_size`, but `None` if the sum overflows
+  pub fn checked_byte_size(&self) -> Option<usize> {
+    self.columns.iter().try_fold(self.null_bitmap_size(), |size, c| size.checked_add(c.typ.checked_byte_size()?))
+  }
+
+  /// Every row starts with a bitmap with a bit for each nullable column, set if the value is NULL\
+  /// Tables without nullable columns don't have it
+  pub fn null_bitmap_size(&self) -> usize {
+    self.columns.iter().filter(|c| c.nullable).count().div_ceil(8)
+  }
+
+  /// Bit of the column in the null bitmap, `None` if the column is not nullable
+  pub fn null_bit(&self, column: usize) -> Option<usize> {
+    self.columns[column].nullable.then(|| self.columns[..column].iter().filter(|c| c.nullable).count())
+  }
+
+  /// Offset of the column inside of the row
+  pub fn column_offset(&self, column: usize) -> usize {
+    self.null_bitmap_size() + self.columns[..column]
+      .iter()
+      .map(|c| c.typ.into_type_tree().byte_size())
+      .sum::<usize>()
+  }
+
+  /// Names of the columns, in the column order
+  pub fn column_names(&self) -> Vec<&str> {
+    let mut names = vec![""; self.columns.len()];
+    for (name, &idx) in &self.column_map {
+      if let Some(slot) = names.get_mut(idx) {
+        *slot = name;
+      }
+    }
+    names
+  }
+
+  /// Rows wider than a sector are spread over several sectors, so a fragment can span more than one\
+  /// Rows never cross fragment boundaries
+  pub fn sectors_per_fragment(&self, sector_size: usize) -> usize {
+    self.byte_size().div_ceil(sector_size).max(1)
+  }
+
+  pub fn rows_per_fragment(&self, sector_size: usize) -> usize {
+    self.sectors_per_fragment(sector_size) * sector_size / self.byte_size()
+  }
+
+  /// Take the next value of the counter
+  pub fn next_counter(&mut self) -> u64 {
+    self.counter += 1;
+    self.counter - 1
+  }
+
+  /// Explicit values of auto-increment columns move the counter past them, so generated values never collide with them
+  pub fn counter_past(&mut self, value: i128) {
+    if let Some(next) = u64::try_from(value).ok().and_then(|value| value.checked_add(1)) {
+      self.counter = self.counter.max(next);
+    }
+  }
+
+  pub fn is_deleted(&self, row: u64) -> bool {
+    self.deleted.contains(row)
+  }
+
+  /// Range of row slots stored in the fragment
+  pub fn fragment_rows(&self, fragment: usize, sector_size: usize) -> Range<u64> {
+    let rows_per_fragment = self.rows_per_fragment(sector_size) as u64;
+    let start = fragment as u64 * rows_per_fragment;
+    start.min(self.row_count)..(start + rows_per_fragment).min(self.row_count)
+  }
+
+  /// Where the row starts: index into `fragmentation` and byte offset inside of that sector
+  pub fn row_location(&self, row: u64, sector_size: usize) -> (usize, usize) {
+    let rows_per_fragment = self.rows_per_fragment(sector_size) as u64;
+    let fragment = (row / rows_per_fragment) as usize;
+    let offset = (row % rows_per_fragment) as usize * self.byte_size();
+    (fragment * self.sectors_per_fragment(sector_size) + offset / sector_size, offset % sector_size)
+  }
 }
 
 impl ReprSize for Table {
   /// returns byte size of ROW, not entire TABLE
   fn byte_size(&self) -> usize {
-    self.columns.iter().map(|c| c.typ.into_type_tree().byte_size()).sum()
+    self.null_bitmap_size() + self.columns.iter().map(|c| c.typ.into_type_tree().byte_size()).sum::<usize>()
   }
 }
 
-#[derive(Serialize, Deserialize, Default, Debug)]
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
 pub struct DbShape {
-  pub reclaim: VecDeque<u64>,
+  /// sectors that can be reused
+  pub free: FreeMap,
   pub table_map: FxHashMap<String, usize>,
   pub tables: Vec<Table>,
+  /// heap pages shared by small heap values, and how many bytes are still free in each of them
+  pub heap_pages: BTreeMap<u64, u32>,
 }
 
 impl DbShape {
@@ -45,4 +176,26 @@ impl DbShape {
   pub fn get_table_mut(&mut self, name: &str) -> Option<&mut Table> {
     self.tables.get_mut(*self.table_map.get(name)?)
   }
+
+  /// Tables after the removed one move one index down, so `table_map` and pointers are updated too\
+  /// Pointers to the removed table point at `DROPPED_TABLE` from then on
+  pub fn remove_table(&mut self, name: &str) -> Option<Table> {
+    let idx = self.table_map.remove(name)?;
+    let table = self.tables.remove(idx);
+    for index in self.table_map.values_mut() {
+      if *index > idx {
+        *index -= 1;
+      }
+    }
+    for column in self.tables.iter_mut().flat_map(|table| table.columns.iter_mut()) {
+      if let Type::Pointer(target) = &mut column.typ {
+        match (*target as usize).cmp(&idx) {
+          Ordering::Greater => *target -= 1,
+          Ordering::Equal => *target = DROPPED_TABLE,
+          Ordering::Less => (),
+        }
+      }
+    }
+    Some(table)
+  }
 }
\ No newline at end of file
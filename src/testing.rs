@@ -0,0 +1,43 @@
+//! helpers shared by unit tests
+
+use std::io::Cursor;
+use anyhow::Result;
+use serde_json::Value;
+use crate::{
+  database::Database,
+  transaction::TransactionId,
+};
+
+pub type MemoryDb = Database<Cursor<Vec<u8>>>;
+
+/// New, already synced database (with a journal) that only lives in memory
+pub fn memory_db() -> MemoryDb {
+  let mut db = Database::with_journal(Cursor::new(vec![]), Cursor::new(vec![])).unwrap();
+  db.sync_database().unwrap();
+  db
+}
+
+/// Perform a request the same way the server does (including the sync), the results are returned as JSON
+pub fn request_in(db: &mut MemoryDb, transaction: Option<TransactionId>, ops: Value) -> Result<(Value, Option<TransactionId>)> {
+  let (results, transaction) = db.perform_request(transaction, serde_json::from_value(ops)?)?;
+  db.sync_database()?;
+  Ok((serde_json::to_value(results)?, transaction))
+}
+
+pub fn request(db: &mut MemoryDb, ops: Value) -> Result<Value> {
+  Ok(request_in(db, None, ops)?.0)
+}
+
+/// Rows returned by a query or a scan, without the row ids
+pub fn rows(result: &Value) -> Vec<Value> {
+  match result {
+    Value::Object(map) if map.contains_key("TableQuery") => map["TableQuery"].as_array().unwrap().clone(),
+    Value::Object(map) if map.contains_key("TableScan") => map["TableScan"]
+      .as_array()
+      .unwrap()
+      .iter()
+      .map(|row| row["columns"].clone())
+      .collect(),
+    other => panic!("not a query result: {other}"),
+  }
+}
@@ -0,0 +1,173 @@
+//! explicit transactions spanning multiple requests\
+//! each open transaction keeps its own private copy of the database state,
+//! which only replaces the main state when it's committed\
+//! committed sectors a transaction can still see are preserved before the main state overwrites them
+
+use std::time::{Duration, Instant};
+use rustc_hash::FxHashMap;
+use crate::database::Snapshot;
+
+pub type TransactionId = u64;
+
+pub const DEFAULT_TRANSACTION_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Clone)]
+pub struct Transaction {
+  /// version of the main state the transaction is based on
+  pub base_version: u64,
+  /// private state of the transaction (not valid while the transaction is being worked on)
+  pub state: Snapshot,
+  pub savepoints: Vec<(String, Snapshot)>,
+  pub last_active: Instant,
+}
+
+pub struct Transactions {
+  open: FxHashMap<TransactionId, Transaction>,
+  /// sectors as they were before the main state overwrote them, for every transaction that began before that\
+  /// kept apart from `open`, as a transaction is taken out (and maybe put back from a copy) while it's worked on
+  preserved: FxHashMap<TransactionId, FxHashMap<u64, Box<[u8]>>>,
+  next_id: TransactionId,
+  /// bumped every time the main state changes\
+  /// transactions based on an older version can't be committed
+  pub version: u64,
+  /// idle transactions are rolled back after this long
+  pub timeout: Duration,
+}
+
+impl Default for Transactions {
+  fn default() -> Self {
+    Self {
+      open: FxHashMap::default(),
+      preserved: FxHashMap::default(),
+      next_id: 1,
+      version: 0,
+      timeout: DEFAULT_TRANSACTION_TIMEOUT,
+    }
+  }
+}
+
+impl Transactions {
+  pub fn begin(&mut self) -> (TransactionId, Transaction) {
+    let id = self.next_id;
+    self.next_id += 1;
+    (id, Transaction {
+      base_version: self.version,
+      state: Snapshot::default(),
+      savepoints: Vec::new(),
+      last_active: Instant::now(),
+    })
+  }
+
+  /// Take the transaction out while it's being worked on
+  pub fn take(&mut self, id: TransactionId) -> Option<Transaction> {
+    self.open.remove(&id)
+  }
+
+  pub fn put_back(&mut self, id: TransactionId, mut transaction: Transaction) {
+    transaction.last_active = Instant::now();
+    self.open.insert(id, transaction);
+  }
+
+  /// Roll back (drop) all transactions that were idle for longer than the timeout\
+  /// Uncommitted changes only live in memory, so there's nothing else to undo
+  pub fn expire(&mut self) {
+    let timeout = self.timeout;
+    self.open.retain(|_, transaction| transaction.last_active.elapsed() < timeout);
+    self.forget_closed();
+  }
+
+  /// Drop the preserved sectors of transactions that were committed, rolled back or expired\
+  /// Only call this while no transaction is taken out
+  pub fn forget_closed(&mut self) {
+    let open = &self.open;
+    self.preserved.retain(|id, _| open.contains_key(id));
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.open.is_empty()
+  }
+
+  /// Keep the committed contents of the sector for every open transaction, unless it has them already
+  pub fn preserve(&mut self, sector: u64, data: &[u8]) {
+    for &id in self.open.keys() {
+      self.preserved.entry(id).or_default().entry(sector).or_insert_with(|| data.into());
+    }
+  }
+
+  /// Contents of the sector the transaction saw before the main state overwrote it
+  pub fn preserved(&self, id: TransactionId, sector: u64) -> Option<&[u8]> {
+    self.preserved.get(&id)?.get(&sector).map(|data| &data[..])
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::{json, Value};
+  use crate::testing::{MemoryDb, memory_db, request, request_in, rows};
+
+  fn query(db: &mut MemoryDb, transaction: Option<u64>, table: &str, row: u64) -> anyhow::Result<Vec<Value>> {
+    let (result, _) = request_in(db, transaction, json!([{"type": "TableQuery", "name": table, "columns": ["x"], "_rowid": row}]))?;
+    Ok(rows(&result[0]))
+  }
+
+  #[test]
+  fn changes_are_only_visible_once_committed() {
+    let mut db = memory_db();
+    request(&mut db, json!([{"type": "TableCreate", "name": "a", "columns": [{"name": "x", "type": {"Text": 8}}]}])).unwrap();
+    let (_, transaction) = request_in(&mut db, None, json!([
+      {"type": "Begin"},
+      {"type": "TableInsert", "name": "a", "columns": ["first"]},
+      {"type": "Savepoint", "name": "one"},
+      {"type": "TableInsert", "name": "a", "columns": ["second"]},
+    ])).unwrap();
+    assert!(query(&mut db, None, "a", 0).is_err());
+    assert_eq!(query(&mut db, transaction, "a", 1).unwrap(), [json!(["second"])]);
+
+    request_in(&mut db, transaction, json!([{"type": "RollbackTo", "name": "one"}, {"type": "Commit"}])).unwrap();
+    assert_eq!(query(&mut db, None, "a", 0).unwrap(), [json!(["first"])]);
+    assert!(query(&mut db, None, "a", 1).is_err());
+  }
+
+  #[test]
+  fn sees_its_snapshot_after_main_state_reuses_sectors() {
+    let mut db = memory_db();
+    request(&mut db, json!([
+      {"type": "TableCreate", "name": "a", "columns": [{"name": "x", "type": {"Text": 600}}]},
+      {"type": "TableInsert", "name": "a", "columns": ["first"]},
+      {"type": "TableInsert", "name": "a", "columns": ["second"]},
+    ])).unwrap();
+    let (_, transaction) = request_in(&mut db, None, json!([{"type": "Begin"}])).unwrap();
+    assert_eq!(query(&mut db, transaction, "a", 1).unwrap(), [json!(["second"])]);
+
+    //the sectors of `a` are freed, and reused by `b`
+    request(&mut db, json!([
+      {"type": "TableDelete", "name": "a"},
+      {"type": "TableCreate", "name": "b", "columns": [{"name": "x", "type": {"Text": 600}}]},
+      {"type": "TableInsert", "name": "b", "columns": ["other"]},
+      {"type": "TableInsert", "name": "b", "columns": ["other"]},
+    ])).unwrap();
+    assert_eq!(query(&mut db, transaction, "a", 0).unwrap(), [json!(["first"])]);
+    assert_eq!(query(&mut db, transaction, "a", 1).unwrap(), [json!(["second"])]);
+    assert!(query(&mut db, None, "a", 0).is_err());
+
+    let err = request_in(&mut db, transaction, json!([{"type": "Commit"}])).unwrap_err();
+    assert!(err.to_string().contains("conflicts"));
+    assert_eq!(query(&mut db, None, "b", 1).unwrap(), [json!(["other"])]);
+  }
+
+  #[test]
+  fn preserved_sectors_are_dropped_with_the_transaction() {
+    let mut db = memory_db();
+    request(&mut db, json!([
+      {"type": "TableCreate", "name": "a", "columns": [{"name": "x", "type": {"Text": 8}}]},
+      {"type": "TableInsert", "name": "a", "columns": ["first"]},
+    ])).unwrap();
+    let (_, transaction) = request_in(&mut db, None, json!([{"type": "Begin"}])).unwrap();
+    //the new row goes into the sector the transaction can still see
+    request(&mut db, json!([{"type": "TableInsert", "name": "a", "columns": ["second"]}])).unwrap();
+    assert!(!db.transactions.preserved.is_empty());
+    request_in(&mut db, transaction, json!([{"type": "Rollback"}])).unwrap();
+    assert!(db.transactions.preserved.is_empty());
+    assert_eq!(query(&mut db, None, "a", 1).unwrap(), [json!(["second"])]);
+  }
+}
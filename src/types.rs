@@ -99,12 +99,23 @@ impl ReprSize for BlobType {
   }
 }
 
+/// Variable-length value stored in the heap, the row only contains a reference to it
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct HeapRefType;
+
+impl ReprSize for HeapRefType {
+  /// first sector + length
+  fn byte_size(&self) -> usize { 16 }
+}
+
 #[derive(Serialize, Deserialize, Clone, Copy, Debug)]
 pub enum TypeTree {
   Pointer(PointerType),
   Number(NumberType),
   Text(TextType),
   Blob(BlobType),
+  VarText(HeapRefType),
+  VarBlob(HeapRefType),
   //TODO Time
 }
 
@@ -115,6 +126,7 @@ impl TypeTree {
       TypeTree::Number(n) => n.byte_size(),
       TypeTree::Text(t) => t.byte_size(),
       TypeTree::Blob(b) => b.byte_size(),
+      TypeTree::VarText(h) | TypeTree::VarBlob(h) => h.byte_size(),
     }
   }
 }
@@ -134,6 +146,9 @@ pub enum Type {
   Float64,
   Text(usize),
   Blob(usize),
+  //new variants must go at the end, as the shape stores the variant index
+  VarText,
+  VarBlob,
 }
 
 impl Type {
@@ -154,6 +169,8 @@ impl Type {
       },
       TypeTree::Blob(b) => Type::Blob(b.size),
       TypeTree::Text(t) => Type::Text(t.size),
+      TypeTree::VarText(_) => Type::VarText,
+      TypeTree::VarBlob(_) => Type::VarBlob,
     }
   }
 
@@ -172,10 +189,30 @@ impl Type {
       Type::Float64 => TypeTree::Number(NumberType::Float(FloatType { size: FloatSize::Float64 })),
       Type::Text(size) => TypeTree::Text(TextType { size }),
       Type::Blob(size) => TypeTree::Blob(BlobType { size }),
+      Type::VarText => TypeTree::VarText(HeapRefType),
+      Type::VarBlob => TypeTree::VarBlob(HeapRefType),
+    }
+  }
+
+  /// Same as `into_type_tree().byte_size()`, but `None` if the size overflows
+  pub fn checked_byte_size(self) -> Option<usize> {
+    match self {
+      Type::Text(size) => size.checked_add(4),
+      typ => Some(typ.into_type_tree().byte_size()),
     }
   }
+
+  /// Whether the value is stored in the heap
+  pub const fn is_heap(self) -> bool {
+    matches!(self, Type::VarText | Type::VarBlob)
+  }
+
+  pub const fn is_integer(self) -> bool {
+    matches!(self.into_type_tree(), TypeTree::Number(NumberType::Integer(_)))
+  }
 }
 
+#[allow(dead_code)]
 impl TypeTree {
   pub const fn from_type(value: Type) -> Self {
     value.into_type_tree()